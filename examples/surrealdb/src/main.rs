@@ -48,7 +48,7 @@ impl Authentication<User, i64, Surreal<Any>> for User {
 
         User::get_user(userid, pool)
             .await
-            .ok_or_else(|| anyhow::anyhow!("Could not load user"))
+            .ok_or_else(|| UserNotFound.into())
     }
 
     fn is_authenticated(&self) -> bool {
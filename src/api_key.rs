@@ -0,0 +1,254 @@
+use crate::{
+    authenticator::bearer_challenge, request::bearer_token, AuthMechanism, AuthRejection,
+    AuthRequest, Authenticated, Authenticator, Scopes,
+};
+use anyhow::Error;
+use async_trait::async_trait;
+use data_encoding::HEXLOWER;
+use http::HeaderMap;
+use rand::RngCore;
+use sha2::{Digest, Sha256};
+use std::{borrow::Cow, collections::HashSet, fmt, sync::Arc};
+
+/// A resolved API key, as returned by ApiKeyStore::lookup.
+///
+#[derive(Debug, Clone)]
+pub struct ApiKey<Type> {
+    /// Id of the user the key acts as.
+    pub id: Type,
+    /// Permissions the key is limited to, as `Scopes::Limit`.
+    pub scopes: HashSet<String>,
+}
+
+/// Where API keys are resolved by their hash_api_key hash.
+///
+/// # Examples
+/// ```rust no_run ignore
+/// #[async_trait]
+/// impl ApiKeyStore<i64, PgPool> for Keys {
+///     async fn lookup(&self, hash: &str, pool: Option<&PgPool>) -> Result<Option<ApiKey<i64>>, anyhow::Error> {
+///         let row: Option<(i64, Vec<String>)> =
+///             sqlx::query_as("SELECT user_id, scopes FROM api_keys WHERE hash = $1 AND revoked = false")
+///                 .bind(hash)
+///                 .fetch_optional(pool.unwrap())
+///                 .await?;
+///
+///         Ok(row.map(|(id, scopes)| ApiKey { id, scopes: scopes.into_iter().collect() }))
+///     }
+/// }
+/// ```
+///
+#[async_trait]
+pub trait ApiKeyStore<Type, Pool>: Send + Sync
+where
+    Type: Send + Sync,
+    Pool: Send + Sync,
+{
+    /// Returns the key with this hash, or None if there is none or it was revoked.
+    async fn lookup(&self, hash: &str, pool: Option<&Pool>) -> Result<Option<ApiKey<Type>>, Error>;
+}
+
+/// Authenticator reading API keys from requests and resolving them through an ApiKeyStore.
+///
+/// Requests with a key are authenticated as the keys user, without using the session's
+/// user id. Requests with an unknown key are rejected with 401 Unauthorized, and with
+/// 503 Service Unavailable if the store fails. The ApiKey is kept in
+/// `AuthSession::auth_extensions`.
+///
+/// # Examples
+/// ```rust no_run ignore
+/// let layer = AuthSessionLayer::<User, i64, SessionPgPool, PgPool>::new(Some(pool))
+///     .with_api_keys(ApiKeys::new(Arc::new(Keys)).with_header("x-api-key"));
+/// ```
+///
+#[derive(Clone)]
+pub struct ApiKeys<Type, Pool> {
+    pub(crate) store: Arc<dyn ApiKeyStore<Type, Pool>>,
+    pub(crate) header: Option<Cow<'static, str>>,
+}
+
+impl<Type, Pool> fmt::Debug for ApiKeys<Type, Pool> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("ApiKeys")
+            .field("header", &self.header)
+            .finish_non_exhaustive()
+    }
+}
+
+impl<Type, Pool> ApiKeys<Type, Pool>
+where
+    Type: Send + Sync,
+    Pool: Send + Sync,
+{
+    /// Creates ApiKeys reading `Authorization: Bearer <key>`.
+    pub fn new(store: Arc<dyn ApiKeyStore<Type, Pool>>) -> Self {
+        Self {
+            store,
+            header: None,
+        }
+    }
+
+    /// Set's a header the key is read from as is, instead of the Authorization Bearer token.
+    ///
+    /// # Examples
+    /// ```rust no_run ignore
+    /// let keys = ApiKeys::new(Arc::new(Keys)).with_header("x-api-key");
+    /// ```
+    ///
+    #[must_use]
+    pub fn with_header(mut self, header: impl Into<Cow<'static, str>>) -> Self {
+        self.header = Some(header.into());
+        self
+    }
+
+    /// Returns the key sent with the request, if any.
+    fn key<'a>(&self, headers: &'a HeaderMap) -> Option<&'a str> {
+        match &self.header {
+            Some(header) => Some(headers.get(header.as_ref())?.to_str().ok()?.trim())
+                .filter(|key| !key.is_empty()),
+            None => bearer_token(headers),
+        }
+    }
+}
+
+#[async_trait]
+impl<Type, Pool> Authenticator<Type, Pool> for ApiKeys<Type, Pool>
+where
+    Type: Clone + Send + Sync + 'static,
+    Pool: Send + Sync,
+{
+    async fn authenticate(
+        &self,
+        request: &AuthRequest<'_, Type, Pool>,
+    ) -> Result<Option<Authenticated<Type>>, AuthRejection> {
+        let Some(key) = self.key(request.headers) else {
+            return Ok(None);
+        };
+
+        let key = self
+            .store
+            .lookup(&hash_api_key(key), request.pool)
+            .await
+            .map_err(AuthRejection::Unavailable)?
+            .ok_or(AuthRejection::Unauthorized(Some(bearer_challenge())))?;
+
+        let mut authenticated = Authenticated::new(key.id.clone(), AuthMechanism::ApiKey);
+        authenticated.scopes = Scopes::Limit(key.scopes.clone());
+        authenticated.extensions.insert(key);
+        Ok(Some(authenticated))
+    }
+}
+
+/// Creates a new random API key. Give it to the client once and store only its hash_api_key hash.
+///
+/// # Examples
+/// ```rust
+/// use axum_session_auth::{generate_api_key, hash_api_key};
+///
+/// let key = generate_api_key();
+/// let hash = hash_api_key(&key);
+/// ```
+///
+pub fn generate_api_key() -> String {
+    let mut bytes = [0u8; 32];
+    rand::thread_rng().fill_bytes(&mut bytes);
+    HEXLOWER.encode(&bytes)
+}
+
+/// Hashes an API key for storage and lookup.
+///
+/// Keys from generate_api_key are random enough that a fast hash is safe to use.
+pub fn hash_api_key(key: &str) -> String {
+    HEXLOWER.encode(&Sha256::digest(key.as_bytes()))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        testing::{Client, TestLayer, User, ADMIN},
+        Auth, DenyReason, Rights,
+    };
+    use http::{Method, Request, StatusCode};
+    use std::collections::HashMap;
+
+    struct Keys(HashMap<String, ApiKey<i64>>);
+
+    #[async_trait]
+    impl ApiKeyStore<i64, ()> for Keys {
+        async fn lookup(
+            &self,
+            hash: &str,
+            _pool: Option<&()>,
+        ) -> Result<Option<ApiKey<i64>>, Error> {
+            Ok(self.0.get(hash).cloned())
+        }
+    }
+
+    fn guard(permission: &str) -> Auth<User, i64, ()> {
+        let mut guard = Auth::build([Method::GET], true);
+        guard.requires(Rights::permission(permission));
+        guard
+    }
+
+    #[tokio::test]
+    async fn keys_are_limited_to_their_scopes() {
+        let key = generate_api_key();
+        let scopes = ["read", "write"].map(String::from).into();
+        let store = Keys(HashMap::from([(
+            hash_api_key(&key),
+            ApiKey { id: ADMIN, scopes },
+        )]));
+        let client =
+            Client::new(TestLayer::new(None).with_api_keys(ApiKeys::new(Arc::new(store)))).await;
+
+        let (_, result) = client
+            .send(
+                Request::get("/").header("authorization", format!("Bearer {key}")),
+                |auth| async move {
+                    (
+                        auth.mechanism.clone(),
+                        auth.api_key().map(|key| key.id),
+                        auth.authorize(&guard("read"), &Method::GET).await,
+                        // The user has admin, but the key does not.
+                        auth.authorize(&guard("admin"), &Method::GET).await,
+                        // The key has write, but the user does not.
+                        auth.authorize(&guard("write"), &Method::GET).await,
+                    )
+                },
+            )
+            .await;
+
+        assert_eq!(
+            result,
+            Some((
+                Some(AuthMechanism::ApiKey),
+                Some(ADMIN),
+                Ok(()),
+                Err(DenyReason::Rights),
+                Err(DenyReason::Rights),
+            ))
+        );
+    }
+
+    #[tokio::test]
+    async fn unknown_keys_get_a_bearer_challenge() {
+        let store = Keys(HashMap::new());
+        let client =
+            Client::new(TestLayer::new(None).with_api_keys(ApiKeys::new(Arc::new(store)))).await;
+
+        let (response, result) = client
+            .send(
+                Request::get("/").header("authorization", format!("Bearer {}", generate_api_key())),
+                |_| async {},
+            )
+            .await;
+
+        assert_eq!(response.status(), StatusCode::UNAUTHORIZED);
+        assert_eq!(
+            response.headers().get(http::header::WWW_AUTHENTICATE),
+            Some(&bearer_challenge())
+        );
+        assert_eq!(result, None);
+    }
+}
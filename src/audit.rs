@@ -0,0 +1,177 @@
+use crate::{DenyReason, RequestInfo};
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+use std::{
+    fmt,
+    fs::OpenOptions,
+    io::{self, Write},
+    net::IpAddr,
+    path::Path,
+    sync::{mpsc, Mutex},
+    thread,
+};
+
+/// What happened in an AuditEvent.
+///
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(tag = "kind", rename_all = "snake_case")]
+pub enum AuditKind {
+    /// A user was logged in with login_user.
+    Login,
+    /// A user was logged out with logout_user.
+    Logout,
+    /// `Auth::check_session`, `Auth::validate_session` or `AuthSession::authorize` denied access.
+    AccessDenied { policy: String, reason: DenyReason },
+    /// A users cached data was cleared.
+    UserInvalidated { target: serde_json::Value },
+    /// All cached users were cleared.
+    AllUsersInvalidated,
+    /// A users cached permission set was cleared, usually as their permissions changed.
+    PermissionsInvalidated { target: serde_json::Value },
+    /// All cached permission sets were cleared.
+    AllPermissionsInvalidated,
+}
+
+/// A security relevant event recorded to an AuditSink.
+///
+/// user_id is the id of the user the event happened to or that did it, as JSON
+/// so sinks do not need to know the user id type.
+///
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct AuditEvent {
+    pub timestamp: DateTime<Utc>,
+    #[serde(flatten)]
+    pub kind: AuditKind,
+    pub user_id: serde_json::Value,
+    pub client_ip: Option<IpAddr>,
+    pub method: String,
+    pub path: String,
+}
+
+impl AuditEvent {
+    pub(crate) fn new<Type: Serialize>(
+        kind: AuditKind,
+        user_id: &Type,
+        request: &RequestInfo,
+    ) -> Self {
+        Self {
+            timestamp: Utc::now(),
+            kind,
+            user_id: serde_json::to_value(user_id).unwrap_or_default(),
+            client_ip: request.client_ip,
+            method: request.method.to_string(),
+            path: request.uri.path().to_owned(),
+        }
+    }
+}
+
+/// Destination for AuditEvents.
+///
+/// record is called inline on the request, so sinks that do slow IO should
+/// hand the event off to a background task.
+///
+/// # Examples
+/// ```rust no_run ignore
+/// let layer = AuthSessionLayer::<User, i64, SessionPgPool, PgPool>::new(Some(pool))
+///     .with_audit_sink(Arc::new(JsonLinesSink::open("audit.log")?));
+/// ```
+///
+pub trait AuditSink: fmt::Debug + Send + Sync {
+    fn record(&self, event: &AuditEvent);
+}
+
+/// AuditSink appending each event as one line of JSON to a file.
+///
+/// Events are written by a dedicated thread, so recording one never blocks the
+/// async runtime on file IO. The thread stops once the sink is dropped.
+///
+#[derive(Debug)]
+pub struct JsonLinesSink {
+    lines: mpsc::Sender<Vec<u8>>,
+}
+
+impl JsonLinesSink {
+    /// Opens the file for appending, creating it if needed, and starts its writer thread.
+    ///
+    /// # Examples
+    /// ```rust no_run ignore
+    /// let sink = JsonLinesSink::open("audit.log")?;
+    /// ```
+    ///
+    pub fn open(path: impl AsRef<Path>) -> io::Result<Self> {
+        let mut file = OpenOptions::new().create(true).append(true).open(path)?;
+        let (lines, receiver) = mpsc::channel::<Vec<u8>>();
+
+        thread::Builder::new()
+            .name("audit-json-lines".into())
+            .spawn(move || {
+                for line in receiver {
+                    if let Err(err) = file.write_all(&line) {
+                        tracing::error!("failed to write audit event: {}", err);
+                    }
+                }
+            })?;
+
+        Ok(Self { lines })
+    }
+}
+
+impl AuditSink for JsonLinesSink {
+    fn record(&self, event: &AuditEvent) {
+        let mut line = match serde_json::to_vec(event) {
+            Ok(line) => line,
+            Err(err) => {
+                tracing::error!("failed to serialize audit event: {}", err);
+                return;
+            }
+        };
+
+        line.push(b'\n');
+
+        if self.lines.send(line).is_err() {
+            tracing::error!("failed to write audit event: the writer thread stopped");
+        }
+    }
+}
+
+/// AuditSink keeping events in memory, mostly for tests.
+///
+/// # Examples
+/// ```rust
+/// use axum_session_auth::MemorySink;
+///
+/// let sink = MemorySink::default();
+/// assert!(sink.events().is_empty());
+/// ```
+///
+#[derive(Debug, Default)]
+pub struct MemorySink {
+    events: Mutex<Vec<AuditEvent>>,
+}
+
+impl MemorySink {
+    /// Returns a copy of every event recorded so far.
+    pub fn events(&self) -> Vec<AuditEvent> {
+        self.events
+            .lock()
+            .unwrap_or_else(|e| e.into_inner())
+            .clone()
+    }
+
+    /// Removes all recorded events.
+    pub fn clear(&self) {
+        self.events
+            .lock()
+            .unwrap_or_else(|e| e.into_inner())
+            .clear();
+    }
+}
+
+impl AuditSink for MemorySink {
+    fn record(&self, event: &AuditEvent) {
+        self.events
+            .lock()
+            .unwrap_or_else(|e| e.into_inner())
+            .push(event.clone());
+    }
+}
@@ -1,9 +1,12 @@
-use crate::Authentication;
+use crate::{telemetry, AuthMechanism, AuthSession, Authentication, PermissionCache, Scopes};
 use async_recursion::async_recursion;
 use async_trait::async_trait;
+use axum_session::DatabasePool;
+use chrono::{DateTime, Duration, Utc};
 use http::Method;
-use serde::{de::DeserializeOwned, Serialize};
-use std::{fmt, hash::Hash, marker::PhantomData};
+use serde::{de::DeserializeOwned, Deserialize, Serialize};
+use std::{borrow::Cow, collections::HashSet, fmt, hash::Hash, marker::PhantomData};
+use tracing::{field, Instrument};
 
 /// Trait is used to check their Permissions via Tokens.
 ///
@@ -15,6 +18,14 @@ where
     Pool: Clone + Send + Sync + fmt::Debug + 'static,
 {
     async fn has(&self, perm: &str, pool: &Option<&Pool>) -> bool;
+
+    /// Loads every permission the user has so they can be cached in a [`PermissionCache`].
+    ///
+    /// When this returns Some, cached Rights checks are evaluated against the set
+    /// instead of calling has(). Returns None by default.
+    async fn load_permissions(&self, _pool: &Option<&Pool>) -> Option<HashSet<String>> {
+        None
+    }
 }
 
 /// Rights enumeration used for building Permissions checks against has() .
@@ -60,12 +71,46 @@ impl Rights {
 
     /// Evaluates all Rights based on the Rights enumeration patterns.
     ///
-    #[async_recursion()]
+    /// Runs within a `rights.evaluate` span recording the auth.decision.
+    ///
     pub async fn evaluate<Pool>(
         &self,
         user: &(dyn HasPermission<Pool> + Sync),
         db: &Option<&Pool>,
     ) -> bool
+    where
+        Pool: Clone + Send + Sync + fmt::Debug + 'static,
+    {
+        let span = Self::span(false);
+        let allowed = self.evaluate_inner(user, db).instrument(span.clone()).await;
+
+        span.record("auth.decision", Self::decision(allowed));
+        allowed
+    }
+
+    fn span(cached: bool) -> tracing::Span {
+        tracing::debug_span!(
+            "rights.evaluate",
+            otel.kind = "internal",
+            auth.permission_cache = cached,
+            auth.decision = field::Empty,
+        )
+    }
+
+    fn decision(allowed: bool) -> &'static str {
+        if allowed {
+            "allow"
+        } else {
+            "deny"
+        }
+    }
+
+    #[async_recursion()]
+    async fn evaluate_inner<Pool>(
+        &self,
+        user: &(dyn HasPermission<Pool> + Sync),
+        db: &Option<&Pool>,
+    ) -> bool
     where
         Pool: Clone + Send + Sync + fmt::Debug + 'static,
     {
@@ -73,7 +118,7 @@ impl Rights {
             Self::All(rights) => {
                 let mut all = true;
                 for r in rights.iter() {
-                    if !r.evaluate(user, db).await {
+                    if !r.evaluate_inner(user, db).await {
                         all = false;
                         break;
                     }
@@ -84,7 +129,7 @@ impl Rights {
             Self::Any(rights) => {
                 let mut all = false;
                 for r in rights.iter() {
-                    if r.evaluate(user, db).await {
+                    if r.evaluate_inner(user, db).await {
                         all = true;
                         break;
                     }
@@ -95,7 +140,7 @@ impl Rights {
             Self::NoneOf(rights) => {
                 let mut all = true;
                 for r in rights.iter() {
-                    if r.evaluate(user, db).await {
+                    if r.evaluate_inner(user, db).await {
                         all = false;
                         break;
                     }
@@ -107,6 +152,83 @@ impl Rights {
             Self::None => false,
         }
     }
+
+    /// Evaluates all Rights against a resolved permission set.
+    ///
+    pub fn evaluate_set(&self, permissions: &HashSet<String>) -> bool {
+        match self {
+            Self::All(rights) => rights.iter().all(|r| r.evaluate_set(permissions)),
+            Self::Any(rights) => rights.iter().any(|r| r.evaluate_set(permissions)),
+            Self::NoneOf(rights) => !rights.iter().any(|r| r.evaluate_set(permissions)),
+            Self::Permission(perm) => permissions.contains(perm),
+            Self::None => false,
+        }
+    }
+
+    /// Evaluates all Rights using the users cached permission set.
+    ///
+    /// Falls back to evaluate if the user does not support load_permissions.
+    ///
+    pub async fn evaluate_cached<Pool, Type>(
+        &self,
+        user: &(dyn HasPermission<Pool> + Sync),
+        id: &Type,
+        cache: &PermissionCache<Type>,
+        db: &Option<&Pool>,
+    ) -> bool
+    where
+        Pool: Clone + Send + Sync + fmt::Debug + 'static,
+        Type: Eq + Default + Clone + Send + Sync + Hash + Serialize + DeserializeOwned + 'static,
+    {
+        let allowed = async {
+            match cache.get_or_load(id, user, db).await {
+                Some(permissions) => self.evaluate_set(&permissions),
+                None => self.evaluate_inner(user, db).await,
+            }
+        };
+
+        let span = Self::span(true);
+        let allowed = allowed.instrument(span.clone()).await;
+
+        span.record("auth.decision", Self::decision(allowed));
+        allowed
+    }
+}
+
+/// Reason an Auth check denied access.
+///
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum DenyReason {
+    /// Authentication is required but the user is not authenticated.
+    Unauthenticated,
+    /// The request method is not one of the allowed methods.
+    Method,
+    /// The user does not have the required Rights.
+    Rights,
+    /// The guard requires a fresh login and the user logged in too long ago.
+    ReauthenticationRequired,
+    /// The request was not authenticated by one of the mechanisms the guard accepts.
+    Mechanism,
+}
+
+impl DenyReason {
+    /// Returns the reason as a short lowercase label.
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            DenyReason::Unauthenticated => "unauthenticated",
+            DenyReason::Method => "method",
+            DenyReason::Rights => "rights",
+            DenyReason::ReauthenticationRequired => "reauthentication_required",
+            DenyReason::Mechanism => "mechanism",
+        }
+    }
+}
+
+impl fmt::Display for DenyReason {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(self.as_str())
+    }
 }
 
 /// Authentication Structure.
@@ -136,6 +258,12 @@ where
     pub rights: Rights,
     pub auth_required: bool,
     pub methods: Vec<Method>,
+    /// Policy name used to label metrics and audit events.
+    pub name: Cow<'static, str>,
+    /// How recent the users login must be, see fresh_within.
+    pub fresh_within: Option<Duration>,
+    /// Mechanisms the request must be authenticated by, see mechanisms. Empty allows any.
+    pub mechanisms: Vec<AuthMechanism>,
     phantom_user: PhantomData<User>,
     phantom_pool: PhantomData<Pool>,
     phantom_type: PhantomData<Type>,
@@ -171,6 +299,9 @@ where
             rights: Rights::None,
             auth_required: auth_req,
             methods: methods.into_iter().collect(),
+            name: Cow::Borrowed("unnamed"),
+            fresh_within: None,
+            mechanisms: Vec::new(),
             phantom_user: Default::default(),
             phantom_pool: Default::default(),
             phantom_type: Default::default(),
@@ -198,9 +329,69 @@ where
         self
     }
 
+    /// Sets the policy name used to label metrics and audit events.
+    ///
+    /// # Examples
+    /// ```rust no_run ignore
+    /// Auth::<User, i64, Pool>::build([Method::POST], true)
+    ///     .named("edit_reports")
+    ///     .requires(Rights::permission("form:editreports"));
+    /// ```
+    ///
+    pub fn named(&mut self, name: impl Into<Cow<'static, str>>) -> &mut Self {
+        self.name = name.into();
+        self
+    }
+
+    /// Requires the user to have logged in or reauthenticated within the duration.
+    ///
+    /// Only session logins count, so requests authenticated by an API key, JWT or Basic
+    /// auth are denied with ReauthenticationRequired like requests without a login time.
+    /// validate and check do not know when the user logged in, so they deny such guards.
+    ///
+    /// # Examples
+    /// ```rust no_run ignore
+    /// auth.authorize(
+    ///     Auth::<User, i64, Pool>::build([Method::POST], true)
+    ///         .named("change_email")
+    ///         .fresh_within(Duration::try_minutes(5).unwrap()),
+    ///     &method,
+    /// )
+    /// .await?;
+    /// ```
+    ///
+    pub fn fresh_within(&mut self, within: Duration) -> &mut Self {
+        self.fresh_within = Some(within);
+        self
+    }
+
+    /// Requires the request to be authenticated by one of these mechanisms.
+    ///
+    /// Requests authenticated by any other mechanism are denied with Mechanism.
+    /// validate and check do not know how the request was authenticated, so they deny
+    /// such guards.
+    ///
+    /// # Examples
+    /// ```rust no_run ignore
+    /// auth.authorize(
+    ///     Auth::<User, i64, Pool>::build([Method::POST], true)
+    ///         .named("rotate_keys")
+    ///         .mechanisms([AuthMechanism::Session]),
+    ///     &method,
+    /// )
+    /// .await?;
+    /// ```
+    ///
+    pub fn mechanisms(&mut self, mechanisms: impl IntoIterator<Item = AuthMechanism>) -> &mut Self {
+        self.mechanisms = mechanisms.into_iter().collect();
+        self
+    }
+
     /// Validates if the Methods MAtch, Rights Exist or do not and If the user is Authenticated.
     ///
     /// Contains an Optional axum_session_database Pool for User auto loading.
+    /// API key and JWT scopes are not applied, use validate_session for requests
+    /// that may be authenticated by them.
     ///
     /// # Examples
     /// ```rust no_run ignore
@@ -216,18 +407,231 @@ where
     /// }
     /// ```
     ///
-    pub async fn validate(&self, user: &User, method: &Method, db: Option<&Pool>) -> bool
+    pub async fn validate(&self, user: &User, method: &Method, db: Option<&Pool>) -> bool {
+        self.check(user, method, db).await.is_ok()
+    }
+
+    /// Validates like validate, but returns why access was denied.
+    ///
+    /// # Examples
+    /// ```rust no_run ignore
+    /// if let Err(reason) = Auth::<User, i64, Pool>::build([Method::POST], true)
+    ///     .requires(Rights::permission("admin:view"))
+    ///     .check(&current_user, &state.method, None)
+    ///     .await
+    /// {
+    ///     return format!("denied: {reason}").into_response();
+    /// }
+    /// ```
+    ///
+    pub async fn check(
+        &self,
+        user: &User,
+        method: &Method,
+        db: Option<&Pool>,
+    ) -> Result<(), DenyReason> {
+        let result = match self.check_user(user, method, None, None) {
+            Ok(()) if self.rights.evaluate(user, &db).await => Ok(()),
+            Ok(()) => Err(DenyReason::Rights),
+            Err(reason) => Err(reason),
+        };
+
+        self.record(result)
+    }
+
+    /// Validates like validate, against the AuthSession's user.
+    ///
+    /// Loads the sessions user first in lazy mode. Rights are checked against the cached
+    /// permission set when the user supports load_permissions, and against the scopes of
+    /// an API key or JWT when the request was authenticated by one. Without a user,
+    /// guards that do not require authentication are checked against User::default().
+    /// Denials are written to the audit sink and passed to the on_access_denied hook.
+    ///
+    /// # Examples
+    /// ```rust no_run ignore
+    /// if !Auth::<User, i64, Pool>::build([Method::POST], true)
+    ///     .requires(Rights::permission("admin:view"))
+    ///     .validate_session(&auth, &state.method)
+    ///     .await
+    /// {
+    ///     return handler_404(state).await.into_response();
+    /// }
+    /// ```
+    ///
+    pub async fn validate_session<Sess>(
+        &self,
+        auth: &AuthSession<User, Type, Sess, Pool>,
+        method: &Method,
+    ) -> bool
+    where
+        User: Default + Clone + 'static,
+        Type: fmt::Display,
+        Sess: DatabasePool + Clone + fmt::Debug + Sync + Send + 'static,
+    {
+        self.check_session(auth, method).await.is_ok()
+    }
+
+    /// Validates like validate_session, but returns why access was denied.
+    ///
+    /// # Examples
+    /// ```rust no_run ignore
+    /// if let Err(reason) = Auth::<User, i64, Pool>::build([Method::POST], true)
+    ///     .requires(Rights::permission("admin:view"))
+    ///     .check_session(&auth, &state.method)
+    ///     .await
+    /// {
+    ///     return format!("denied: {reason}").into_response();
+    /// }
+    /// ```
+    ///
+    pub async fn check_session<Sess>(
+        &self,
+        auth: &AuthSession<User, Type, Sess, Pool>,
+        method: &Method,
+    ) -> Result<(), DenyReason>
     where
-        User: HasPermission<Pool> + Authentication<User, Type, Pool>,
+        User: Default + Clone + 'static,
+        Type: fmt::Display,
+        Sess: DatabasePool + Clone + fmt::Debug + Sync + Send + 'static,
     {
+        let user = match auth.user().await {
+            Ok(Some(user)) => user,
+            Ok(None) if !self.auth_required => User::default(),
+            _ => {
+                return self
+                    .record_session(auth, Err(DenyReason::Unauthenticated))
+                    .await
+            }
+        };
+
+        let logged_in_at = auth.session_login_info().map(|info| info.at);
+        let result = match self.check_user(&user, method, logged_in_at, auth.mechanism.as_ref()) {
+            Err(reason) => Err(reason),
+            // Replacing scopes, like a JWT's scope claim, are used instead of the users own permissions.
+            Ok(()) => match &auth.scopes {
+                Scopes::Replace(scopes) => self.rights.evaluate_set(scopes),
+                // Limiting scopes, like an API key's, apply on top of the users own permissions.
+                Scopes::Limit(scopes) => {
+                    self.rights.evaluate_set(scopes) && self.evaluate_rights(&user, auth).await
+                }
+                Scopes::User => self.evaluate_rights(&user, auth).await,
+            }
+            .then_some(())
+            .ok_or(DenyReason::Rights),
+        };
+
+        self.record_session(auth, result).await
+    }
+
+    /// Records the decision to metrics, and denials to the sessions audit sink and hooks.
+    async fn record_session<Sess>(
+        &self,
+        auth: &AuthSession<User, Type, Sess, Pool>,
+        result: Result<(), DenyReason>,
+    ) -> Result<(), DenyReason>
+    where
+        User: Clone + 'static,
+        Type: fmt::Display,
+        Sess: DatabasePool + Clone + fmt::Debug + Sync + Send + 'static,
+    {
+        if let Err(reason) = result {
+            auth.record_denial(&self.name, reason).await;
+        }
+
+        self.record(result)
+    }
+
+    /// Evaluates the Rights against the users cached permission set.
+    async fn evaluate_rights<Sess>(
+        &self,
+        user: &User,
+        auth: &AuthSession<User, Type, Sess, Pool>,
+    ) -> bool
+    where
+        User: Clone + 'static,
+        Type: fmt::Display,
+        Sess: DatabasePool + Clone + fmt::Debug + Sync + Send + 'static,
+    {
+        self.rights
+            .evaluate_cached(user, &auth.id, &auth.cache.permissions, &auth.pool.as_ref())
+            .await
+    }
+
+    /// Checks the authentication, method, freshness and mechanism requirements.
+    pub(crate) fn check_user(
+        &self,
+        user: &User,
+        method: &Method,
+        logged_in_at: Option<DateTime<Utc>>,
+        mechanism: Option<&AuthMechanism>,
+    ) -> Result<(), DenyReason> {
         if self.auth_required && !user.is_authenticated() {
-            return false;
+            return Err(DenyReason::Unauthenticated);
         }
 
-        if self.methods.iter().any(|r| r == method) {
-            self.rights.evaluate(user, &db).await
-        } else {
-            false
+        if !self.methods.iter().any(|r| r == method) {
+            return Err(DenyReason::Method);
+        }
+
+        if let Some(within) = self.fresh_within {
+            if logged_in_at.is_none_or(|at| Utc::now() - at > within) {
+                return Err(DenyReason::ReauthenticationRequired);
+            }
         }
+
+        if !self.mechanisms.is_empty()
+            && mechanism.is_none_or(|mechanism| !self.mechanisms.contains(mechanism))
+        {
+            return Err(DenyReason::Mechanism);
+        }
+
+        Ok(())
+    }
+
+    /// Records the decision to metrics.
+    fn record(&self, result: Result<(), DenyReason>) -> Result<(), DenyReason> {
+        match result {
+            Ok(()) => telemetry::guard(&self.name, true, "allowed"),
+            Err(reason) => telemetry::guard(&self.name, false, reason.as_str()),
+        }
+
+        result
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::{User, ADMIN, GUEST};
+
+    #[tokio::test]
+    async fn check_tells_why_access_was_denied() {
+        let admin = User::load_user(ADMIN, None).await.unwrap();
+        let guest = User::load_user(GUEST, None).await.unwrap();
+        let reader = User::load_user(70, None).await.unwrap();
+
+        let mut guard = Auth::<User, i64, ()>::build([Method::GET], true);
+        guard.requires(Rights::permission("admin"));
+
+        assert_eq!(guard.check(&admin, &Method::GET, None).await, Ok(()));
+        assert_eq!(
+            guard.check(&admin, &Method::POST, None).await,
+            Err(DenyReason::Method)
+        );
+        assert_eq!(
+            guard.check(&guest, &Method::GET, None).await,
+            Err(DenyReason::Unauthenticated)
+        );
+        assert_eq!(
+            guard.check(&reader, &Method::GET, None).await,
+            Err(DenyReason::Rights)
+        );
+
+        // check does not know when the user logged in.
+        guard.fresh_within(Duration::try_minutes(5).unwrap());
+        assert_eq!(
+            guard.check(&admin, &Method::GET, None).await,
+            Err(DenyReason::ReauthenticationRequired)
+        );
     }
 }
@@ -0,0 +1,171 @@
+use crate::LoginLimiter;
+use anyhow::Error;
+use async_trait::async_trait;
+use http::{Extensions, HeaderMap, HeaderValue, Method, Uri};
+use serde::{Deserialize, Serialize};
+use std::{borrow::Cow, collections::HashSet, fmt, net::IpAddr};
+
+/// How a request was authenticated.
+///
+#[derive(Debug, Clone, PartialEq, Eq, Hash, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum AuthMechanism {
+    /// The user id stored in the axum_session session.
+    Session,
+    /// An API key, see ApiKeys.
+    ApiKey,
+    /// A JWT, see JwtAuth.
+    Jwt,
+    /// HTTP Basic credentials, see BasicAuth.
+    Basic,
+    /// A mechanism of a custom Authenticator.
+    Custom(Cow<'static, str>),
+}
+
+impl AuthMechanism {
+    /// Returns the mechanism as a short lowercase label.
+    pub fn as_str(&self) -> &str {
+        match self {
+            AuthMechanism::Session => "session",
+            AuthMechanism::ApiKey => "api_key",
+            AuthMechanism::Jwt => "jwt",
+            AuthMechanism::Basic => "basic",
+            AuthMechanism::Custom(name) => name,
+        }
+    }
+}
+
+impl fmt::Display for AuthMechanism {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(self.as_str())
+    }
+}
+
+/// How the permissions of an authenticated request relate to the users own.
+///
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub enum Scopes {
+    /// Only the users own permissions are used.
+    #[default]
+    User,
+    /// The users permissions are further limited to these, like for a scoped API key.
+    Limit(HashSet<String>),
+    /// These are used instead of the users permissions, like a JWT's scope claim.
+    Replace(HashSet<String>),
+}
+
+/// What an Authenticator gets to look at.
+///
+pub struct AuthRequest<'a, Type, Pool> {
+    pub method: &'a Method,
+    pub uri: &'a Uri,
+    pub headers: &'a HeaderMap,
+    /// Client address read from the configured client ip header, if set and valid.
+    pub client_ip: Option<IpAddr>,
+    /// The user id stored in the session, if any.
+    pub session_user: Option<&'a Type>,
+    pub pool: Option<&'a Pool>,
+    /// The configured LoginLimiter, for authenticators checking credentials like passwords.
+    pub limiter: Option<&'a LoginLimiter>,
+}
+
+/// A request an Authenticator recognised.
+///
+#[derive(Debug, Clone)]
+pub struct Authenticated<Type> {
+    pub id: Type,
+    pub mechanism: AuthMechanism,
+    pub scopes: Scopes,
+    /// Log the user into the session with login_user_with_hooks, if they are not already.
+    /// Ignored when a second factor is required.
+    pub login: bool,
+    /// Mechanism specific data, readable from `AuthSession::auth_extensions`.
+    pub extensions: Extensions,
+}
+
+impl<Type> Authenticated<Type> {
+    /// Creates an Authenticated using the users own permissions.
+    pub fn new(id: Type, mechanism: AuthMechanism) -> Self {
+        Self {
+            id,
+            mechanism,
+            scopes: Scopes::User,
+            login: false,
+            extensions: Extensions::new(),
+        }
+    }
+}
+
+/// Why an Authenticator refused a request.
+///
+#[derive(Debug)]
+pub enum AuthRejection {
+    /// The credentials are invalid. Responds 401 with the challenge as `WWW-Authenticate`.
+    Unauthorized(Option<HeaderValue>),
+    /// The credentials could not be checked. Responds 503.
+    Unavailable(Error),
+    /// Too many failed attempts. Responds 429 with retry_after as `Retry-After`.
+    TooManyAttempts { retry_after: chrono::Duration },
+}
+
+/// A source of authentication for AuthSessionService.
+///
+/// The layer asks its authenticators in order. The first to return Authenticated sets the
+/// requests user, and a rejection ends the request. Ok(None) passes the request on.
+///
+/// # Examples
+/// ```rust no_run ignore
+/// #[derive(Debug)]
+/// struct MutualTls;
+///
+/// #[async_trait]
+/// impl Authenticator<i64, PgPool> for MutualTls {
+///     async fn authenticate(&self, request: &AuthRequest<'_, i64, PgPool>) -> Result<Option<Authenticated<i64>>, AuthRejection> {
+///         let Some(cn) = request.headers.get("x-client-cert-cn") else {
+///             return Ok(None);
+///         };
+///
+///         let id = lookup_service_account(cn, request.pool).await.map_err(AuthRejection::Unavailable)?;
+///         Ok(id.map(|id| Authenticated::new(id, AuthMechanism::Custom("mtls".into()))))
+///     }
+/// }
+/// ```
+///
+#[async_trait]
+pub trait Authenticator<Type, Pool>: fmt::Debug + Send + Sync
+where
+    Type: Send + Sync,
+    Pool: Send + Sync,
+{
+    async fn authenticate(
+        &self,
+        request: &AuthRequest<'_, Type, Pool>,
+    ) -> Result<Option<Authenticated<Type>>, AuthRejection>;
+}
+
+/// Authenticator using the user id stored in the session, the default when no chain is set.
+///
+#[derive(Debug, Clone, Copy, Default)]
+pub struct SessionAuthenticator;
+
+#[async_trait]
+impl<Type, Pool> Authenticator<Type, Pool> for SessionAuthenticator
+where
+    Type: Clone + Send + Sync,
+    Pool: Send + Sync,
+{
+    async fn authenticate(
+        &self,
+        request: &AuthRequest<'_, Type, Pool>,
+    ) -> Result<Option<Authenticated<Type>>, AuthRejection> {
+        Ok(request
+            .session_user
+            .map(|id| Authenticated::new(id.clone(), AuthMechanism::Session)))
+    }
+}
+
+/// Challenge sent with a rejected bearer token.
+#[cfg(any(feature = "api-key", feature = "jwt"))]
+pub(crate) fn bearer_challenge() -> HeaderValue {
+    HeaderValue::from_static("Bearer error=\"invalid_token\"")
+}
@@ -0,0 +1,330 @@
+use crate::{AttemptKey, AuthMechanism, AuthRejection, AuthRequest, Authenticated, Authenticator};
+use anyhow::Error;
+use async_trait::async_trait;
+use base64::{engine::general_purpose::STANDARD, Engine};
+use http::{header::AUTHORIZATION, HeaderValue};
+use std::{borrow::Cow, fmt, sync::Arc};
+
+/// Checks HTTP Basic credentials.
+///
+/// # Examples
+/// ```rust no_run ignore
+/// #[async_trait]
+/// impl CredentialVerifier<i64, PgPool> for Users {
+///     async fn verify(&self, username: &str, password: &str, pool: Option<&PgPool>) -> Result<Option<i64>, anyhow::Error> {
+///         let user = User::find_by_name(username, pool.unwrap()).await?;
+///
+///         Ok(user.filter(|user| hashing.verify(password, &user.hash).unwrap_or(false)).map(|user| user.id))
+///     }
+/// }
+/// ```
+///
+#[async_trait]
+pub trait CredentialVerifier<Type, Pool>: Send + Sync
+where
+    Type: Send + Sync,
+    Pool: Send + Sync,
+{
+    /// Returns the id of the user if the username and password are valid.
+    async fn verify(
+        &self,
+        username: &str,
+        password: &str,
+        pool: Option<&Pool>,
+    ) -> Result<Option<Type>, Error>;
+}
+
+/// What a request with valid Basic credentials does to the session.
+///
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum BasicAuthMode {
+    /// Only the request is authenticated, the session is left untouched.
+    #[default]
+    PerRequest,
+    /// The user is logged into the session with login_user_with_hooks, so later requests
+    /// can use the session cookie instead. Acts like PerRequest when
+    /// AuthConfig::with_second_factor is enabled.
+    Session,
+}
+
+/// Authenticator accepting `Authorization: Basic` credentials, checked by a CredentialVerifier.
+///
+/// Requests with wrong credentials are rejected with 401 Unauthorized and a
+/// `WWW-Authenticate: Basic` challenge, with 429 Too Many Requests once the LoginLimiter
+/// set with AuthConfig::with_login_limiter blocks the username or client ip, and with 503 Service Unavailable if the verifier fails.
+/// Only use this over https, as the password is sent with every request.
+///
+/// # Examples
+/// ```rust no_run ignore
+/// let layer = AuthSessionLayer::<User, i64, SessionPgPool, PgPool>::new(Some(pool))
+///     .with_basic_auth(BasicAuth::new(Arc::new(Users)).with_realm("admin tools"));
+/// ```
+///
+#[derive(Clone)]
+pub struct BasicAuth<Type, Pool> {
+    pub(crate) verifier: Arc<dyn CredentialVerifier<Type, Pool>>,
+    pub(crate) realm: Cow<'static, str>,
+    pub(crate) mode: BasicAuthMode,
+}
+
+impl<Type, Pool> fmt::Debug for BasicAuth<Type, Pool> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("BasicAuth")
+            .field("realm", &self.realm)
+            .field("mode", &self.mode)
+            .finish_non_exhaustive()
+    }
+}
+
+impl<Type, Pool> BasicAuth<Type, Pool>
+where
+    Type: Send + Sync,
+    Pool: Send + Sync,
+{
+    /// Creates BasicAuth with the realm `restricted` in PerRequest mode.
+    pub fn new(verifier: Arc<dyn CredentialVerifier<Type, Pool>>) -> Self {
+        Self {
+            verifier,
+            realm: "restricted".into(),
+            mode: BasicAuthMode::default(),
+        }
+    }
+
+    /// Set's the realm sent in the challenge.
+    ///
+    /// # Examples
+    /// ```rust no_run ignore
+    /// let basic = BasicAuth::new(Arc::new(Users)).with_realm("admin tools");
+    /// ```
+    ///
+    #[must_use]
+    pub fn with_realm(mut self, realm: impl Into<Cow<'static, str>>) -> Self {
+        self.realm = realm.into();
+        self
+    }
+
+    /// Set's if valid credentials log the user into the session. Defaults to PerRequest.
+    ///
+    /// # Examples
+    /// ```rust no_run ignore
+    /// let basic = BasicAuth::new(Arc::new(Users)).with_mode(BasicAuthMode::Session);
+    /// ```
+    ///
+    #[must_use]
+    pub fn with_mode(mut self, mode: BasicAuthMode) -> Self {
+        self.mode = mode;
+        self
+    }
+
+    /// Returns the `WWW-Authenticate` value challenging for Basic credentials.
+    pub fn challenge(&self) -> HeaderValue {
+        let realm = self.realm.replace(['\\', '"'], "");
+
+        HeaderValue::from_str(&format!("Basic realm=\"{}\", charset=\"UTF-8\"", realm))
+            .unwrap_or_else(|_| HeaderValue::from_static("Basic charset=\"UTF-8\""))
+    }
+
+    fn rejection(&self) -> AuthRejection {
+        AuthRejection::Unauthorized(Some(self.challenge()))
+    }
+}
+
+#[async_trait]
+impl<Type, Pool> Authenticator<Type, Pool> for BasicAuth<Type, Pool>
+where
+    Type: Send + Sync,
+    Pool: Send + Sync,
+{
+    async fn authenticate(
+        &self,
+        request: &AuthRequest<'_, Type, Pool>,
+    ) -> Result<Option<Authenticated<Type>>, AuthRejection> {
+        let headers = request.headers;
+        let Some(value) = headers.get(AUTHORIZATION).and_then(|v| v.to_str().ok()) else {
+            return Ok(None);
+        };
+
+        let Some((scheme, credentials)) = value.split_once(' ') else {
+            return Ok(None);
+        };
+
+        if !scheme.eq_ignore_ascii_case("basic") {
+            return Ok(None);
+        }
+
+        let decoded = STANDARD
+            .decode(credentials.trim())
+            .ok()
+            .and_then(|bytes| String::from_utf8(bytes).ok())
+            .ok_or_else(|| self.rejection())?;
+
+        let (username, password) = decoded.split_once(':').ok_or_else(|| self.rejection())?;
+
+        let mut keys = vec![AttemptKey::username(username)];
+
+        if let Some(ip) = request.client_ip {
+            keys.push(AttemptKey::Ip(ip));
+        }
+
+        // Counted as failed before the password is checked, so a burst of requests can
+        // not all be checked before the first failure is recorded.
+        let attempt = match request.limiter {
+            Some(limiter) => match limiter
+                .reserve(&keys)
+                .await
+                .map_err(AuthRejection::Unavailable)?
+            {
+                Ok(attempt) => Some(attempt),
+                Err(retry_after) => return Err(AuthRejection::TooManyAttempts { retry_after }),
+            },
+            None => None,
+        };
+
+        let id = self
+            .verifier
+            .verify(username, password, request.pool)
+            .await
+            .map_err(AuthRejection::Unavailable)?;
+
+        if let (Some(_), Some(attempt)) = (&id, attempt) {
+            // The ip is kept so one valid account can not reset the limit for a whole ip.
+            attempt
+                .release(|key| !matches!(key, AttemptKey::Ip(_)))
+                .await
+                .map_err(AuthRejection::Unavailable)?;
+        }
+
+        let id = id.ok_or_else(|| self.rejection())?;
+
+        let mut authenticated = Authenticated::new(id, AuthMechanism::Basic);
+        authenticated.login = self.mode == BasicAuthMode::Session;
+        Ok(Some(authenticated))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        testing::{Client, TestLayer},
+        AuthConfig, LoginLimiter,
+    };
+    use http::{header, Request, StatusCode};
+
+    struct Users;
+
+    #[async_trait]
+    impl CredentialVerifier<i64, ()> for Users {
+        async fn verify(
+            &self,
+            username: &str,
+            password: &str,
+            _pool: Option<&()>,
+        ) -> Result<Option<i64>, Error> {
+            match (username, password) {
+                ("broken", _) => Err(anyhow::anyhow!("database is down")),
+                ("alice", "secret") => Ok(Some(91)),
+                _ => Ok(None),
+            }
+        }
+    }
+
+    fn basic(credentials: &str) -> http::request::Builder {
+        Request::get("/").header(
+            header::AUTHORIZATION,
+            format!("Basic {}", STANDARD.encode(credentials)),
+        )
+    }
+
+    async fn client(mode: BasicAuthMode) -> Client {
+        let limiter = LoginLimiter::default().with_free_attempts(1).with_backoff(
+            chrono::Duration::try_seconds(10).unwrap(),
+            chrono::Duration::try_minutes(1).unwrap(),
+        );
+
+        Client::new(
+            TestLayer::new(None)
+                .with_config(AuthConfig::default().with_login_limiter(Some(limiter)))
+                .with_basic_auth(
+                    BasicAuth::new(Arc::new(Users))
+                        .with_realm("admin tools")
+                        .with_mode(mode),
+                ),
+        )
+        .await
+    }
+
+    #[tokio::test]
+    async fn valid_credentials_authenticate_the_request() {
+        let client = client(BasicAuthMode::PerRequest).await;
+
+        let (_, result) = client
+            .send(basic("alice:secret"), |auth| async move {
+                (auth.id, auth.mechanism.clone())
+            })
+            .await;
+        assert_eq!(result, Some((91, Some(AuthMechanism::Basic))));
+
+        // PerRequest leaves the session alone.
+        let (_, result) = client
+            .get(|auth| async move { auth.mechanism.clone() })
+            .await;
+        assert_eq!(result, Some(None));
+
+        // Other schemes are left to the rest of the chain.
+        let (response, _) = client
+            .send(
+                Request::get("/").header(header::AUTHORIZATION, "Digest username=\"alice\""),
+                |_| async {},
+            )
+            .await;
+        assert_eq!(response.status(), StatusCode::OK);
+    }
+
+    #[tokio::test]
+    async fn session_mode_logs_the_user_in() {
+        let client = client(BasicAuthMode::Session).await;
+
+        client.send(basic("alice:secret"), |_| async {}).await;
+        let (_, result) = client
+            .get(|auth| async move { (auth.id, auth.mechanism.clone()) })
+            .await;
+
+        assert_eq!(result, Some((91, Some(AuthMechanism::Session))));
+    }
+
+    #[tokio::test]
+    async fn wrong_credentials_are_challenged_and_then_limited() {
+        let client = client(BasicAuthMode::PerRequest).await;
+
+        let (response, result) = client.send(basic("alice:wrong"), |_| async {}).await;
+        assert_eq!(response.status(), StatusCode::UNAUTHORIZED);
+        assert_eq!(
+            response.headers().get(header::WWW_AUTHENTICATE).unwrap(),
+            "Basic realm=\"admin tools\", charset=\"UTF-8\""
+        );
+        assert_eq!(result, None);
+
+        // Credentials that can not be read are refused without being counted.
+        let (response, _) = client.send(basic("not base64"), |_| async {}).await;
+        assert_eq!(response.status(), StatusCode::UNAUTHORIZED);
+
+        let (response, _) = client.send(basic("alice:wrong"), |_| async {}).await;
+        assert_eq!(response.status(), StatusCode::UNAUTHORIZED);
+
+        // Even the right password is refused while the username is blocked.
+        let (response, result) = client.send(basic("alice:secret"), |_| async {}).await;
+        assert_eq!(response.status(), StatusCode::TOO_MANY_REQUESTS);
+        assert_eq!(response.headers().get(header::RETRY_AFTER).unwrap(), "10");
+        assert_eq!(result, None);
+    }
+
+    #[tokio::test]
+    async fn verifier_errors_are_unavailable() {
+        let client = client(BasicAuthMode::PerRequest).await;
+        let (response, result) = client.send(basic("broken:secret"), |_| async {}).await;
+
+        assert_eq!(response.status(), StatusCode::SERVICE_UNAVAILABLE);
+        assert_eq!(result, None);
+    }
+}
@@ -0,0 +1,178 @@
+use futures::stream::{self, BoxStream};
+use serde::{de::DeserializeOwned, Deserialize, Serialize};
+use std::{fmt, hash::Hash};
+use tokio::sync::broadcast::{self, error::RecvError};
+
+/// Cache invalidation sent between app instances over an [`InvalidationBus`].
+///
+/// This implements Serialize and Deserialize so bus adapters can send it as a message payload.
+///
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub enum Invalidation<Type> {
+    /// Clear a single user from the cache.
+    User(Type),
+    /// Clear all users from the cache.
+    All,
+    /// Clear a single user's permission set from the permission cache.
+    Permissions(Type),
+    /// Clear all permission sets from the permission cache.
+    AllPermissions,
+}
+
+/// Trait used to share cache invalidations between multiple app instances.
+///
+/// `AuthSession::cache_clear_user` and `AuthSession::cache_clear_all` clear the local
+/// cache and then publish to the bus. Every [`AuthCache`](crate::AuthCache) the bus is set on
+/// subscribes to it and clears what it receives, including its own messages.
+///
+/// # Examples
+/// An adapter for a pub/sub server like Redis publishes the serialized [`Invalidation`]
+/// and forwards a subscription of the same channel as the stream.
+/// ```rust no_run ignore
+/// #[derive(Debug)]
+/// struct RedisBus {
+///     client: PubSubClient,
+/// }
+///
+/// impl InvalidationBus<i64> for RedisBus {
+///     fn publish(&self, message: Invalidation<i64>) {
+///         let client = self.client.clone();
+///         let payload = serde_json::to_string(&message).unwrap();
+///
+///         tokio::spawn(async move { client.publish("auth_invalidation", payload).await });
+///     }
+///
+///     fn subscribe(&self) -> BoxStream<'static, Invalidation<i64>> {
+///         self.client
+///             .subscribe("auth_invalidation")
+///             .filter_map(|payload| async move { serde_json::from_str(&payload).ok() })
+///             .boxed()
+///     }
+/// }
+/// ```
+///
+pub trait InvalidationBus<Type>: fmt::Debug + Send + Sync
+where
+    Type: Eq + Default + Clone + Send + Sync + Hash + Serialize + DeserializeOwned + 'static,
+{
+    /// Sends the invalidation to all subscribers. Must not block.
+    fn publish(&self, message: Invalidation<Type>);
+
+    /// Returns a stream of all invalidations published after this call.
+    fn subscribe(&self) -> BoxStream<'static, Invalidation<Type>>;
+}
+
+/// In-process [`InvalidationBus`] using a tokio broadcast channel.
+///
+/// Useful for tests or to share invalidations between multiple layers in a single process.
+/// If a subscriber falls behind it clears all its cached users and permission sets.
+///
+/// # Examples
+/// ```rust
+/// use axum_session_auth::BroadcastBus;
+///
+/// let bus = BroadcastBus::<i64>::new(64);
+/// ```
+///
+#[derive(Debug, Clone)]
+pub struct BroadcastBus<Type> {
+    sender: broadcast::Sender<Invalidation<Type>>,
+}
+
+impl<Type> BroadcastBus<Type>
+where
+    Type: Eq + Default + Clone + Send + Sync + Hash + Serialize + DeserializeOwned + 'static,
+{
+    /// Creates a new BroadcastBus which holds up to capacity unreceived messages per subscriber.
+    pub fn new(capacity: usize) -> Self {
+        let (sender, _) = broadcast::channel(capacity);
+
+        Self { sender }
+    }
+}
+
+impl<Type> InvalidationBus<Type> for BroadcastBus<Type>
+where
+    Type: Eq
+        + Default
+        + Clone
+        + Send
+        + Sync
+        + Hash
+        + Serialize
+        + DeserializeOwned
+        + fmt::Debug
+        + 'static,
+{
+    fn publish(&self, message: Invalidation<Type>) {
+        // Sending only fails if there are no subscribers.
+        let _ = self.sender.send(message);
+    }
+
+    fn subscribe(&self) -> BoxStream<'static, Invalidation<Type>> {
+        Box::pin(stream::unfold(
+            (self.sender.subscribe(), false),
+            |(mut receiver, lagged)| async move {
+                // The missed messages may have cleared users or permissions, so both are cleared.
+                if lagged {
+                    return Some((Invalidation::AllPermissions, (receiver, false)));
+                }
+
+                match receiver.recv().await {
+                    Ok(message) => Some((message, (receiver, false))),
+                    Err(RecvError::Lagged(_)) => Some((Invalidation::All, (receiver, true))),
+                    Err(RecvError::Closed) => None,
+                }
+            },
+        ))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::{Client, TestLayer};
+    use futures::StreamExt;
+    use std::{sync::Arc, time::Duration};
+
+    #[tokio::test]
+    async fn lagged_subscribers_clear_users_and_permissions() {
+        let bus = BroadcastBus::<i64>::new(1);
+        let mut messages = bus.subscribe();
+
+        bus.publish(Invalidation::User(1));
+        bus.publish(Invalidation::Permissions(2));
+        bus.publish(Invalidation::User(3));
+
+        assert_eq!(messages.next().await, Some(Invalidation::All));
+        assert_eq!(messages.next().await, Some(Invalidation::AllPermissions));
+        assert_eq!(messages.next().await, Some(Invalidation::User(3)));
+    }
+
+    #[tokio::test]
+    async fn clears_are_delivered_to_every_layer_on_the_bus() {
+        let bus = Arc::new(BroadcastBus::<i64>::new(16));
+        let first = TestLayer::new(None).with_invalidation_bus(bus.clone());
+        let second = TestLayer::new(None).with_invalidation_bus(bus);
+        let cache = second.cache().clone();
+        let cached = |id| cache.entries().any(|(cached, _)| cached == id);
+
+        assert_eq!(second.warm_cache([20, 21]).await, 2);
+
+        Client::new(first)
+            .await
+            .get(|auth| async move { auth.cache_clear_user(20) })
+            .await;
+
+        for _ in 0..100 {
+            if !cached(20) {
+                break;
+            }
+
+            tokio::time::sleep(Duration::from_millis(5)).await;
+        }
+
+        assert!(!cached(20));
+        assert!(cached(21));
+    }
+}
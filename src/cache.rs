@@ -1,10 +1,59 @@
-use crate::{AuthUser, Authentication};
+use crate::{
+    telemetry, AuthConfig, AuthUser, Authentication, Degraded, Invalidation, InvalidationBus,
+    LoadFailure, LoadTimeout, PermissionCache,
+};
+use anyhow::Error;
 use chrono::{DateTime, Utc};
-use dashmap::DashMap;
+use dashmap::{DashMap, DashSet};
+use futures::StreamExt;
 use serde::{de::DeserializeOwned, Serialize};
-use std::{fmt, hash::Hash, marker::PhantomData, sync::Arc};
+use std::{
+    fmt,
+    hash::Hash,
+    marker::PhantomData,
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc,
+    },
+    time::{Duration, Instant},
+};
 use tokio::sync::RwLock;
 
+/// Snapshot of the user cache's counters returned by [`AuthCache::stats`].
+///
+/// Counters are totals since the cache was created.
+///
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct CacheStats {
+    /// Requests served from the cache.
+    pub hits: u64,
+    /// Requests that had to call load_user.
+    pub misses: u64,
+    /// load_user calls that failed with a transient error.
+    pub load_failures: u64,
+    /// Users removed by expiry sweeps.
+    pub evictions: u64,
+    /// Number of expiry sweeps run.
+    pub sweeps: u64,
+    /// How long the last expiry sweep took.
+    pub last_sweep_duration: Duration,
+    /// How long all expiry sweeps took together.
+    pub total_sweep_duration: Duration,
+    /// Number of users currently cached.
+    pub size: usize,
+}
+
+#[derive(Debug, Default)]
+pub(crate) struct CacheCounters {
+    pub(crate) hits: AtomicU64,
+    pub(crate) misses: AtomicU64,
+    pub(crate) load_failures: AtomicU64,
+    pub(crate) evictions: AtomicU64,
+    pub(crate) sweeps: AtomicU64,
+    pub(crate) last_sweep_micros: AtomicU64,
+    pub(crate) total_sweep_micros: AtomicU64,
+}
+
 #[derive(Clone)]
 pub struct AuthCache<User, Type, Pool>
 where
@@ -14,6 +63,13 @@ where
 {
     pub(crate) last_expiry_sweep: Arc<RwLock<DateTime<Utc>>>,
     pub(crate) inner: Arc<DashMap<Type, AuthUser<User, Type, Pool>>>,
+    /// User ids that currently have a refresh-ahead load running.
+    pub(crate) refreshing: Arc<DashSet<Type>>,
+    /// Bus used to share cache clears with other app instances.
+    pub(crate) bus: Option<Arc<dyn InvalidationBus<Type>>>,
+    pub(crate) counters: Arc<CacheCounters>,
+    /// Resolved permission sets, used even if user caching is disabled.
+    pub(crate) permissions: PermissionCache<Type>,
     pub phantom: PhantomData<Pool>,
 }
 
@@ -26,6 +82,7 @@ where
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
         f.debug_struct("AuthCache")
             .field("last_expiry_sweep", &self.last_expiry_sweep)
+            .field("bus", &self.bus)
             .finish()
     }
 }
@@ -40,7 +97,730 @@ where
         Self {
             last_expiry_sweep: Arc::new(RwLock::new(last_expiry_sweep)),
             inner: Arc::new(DashMap::default()),
+            refreshing: Arc::new(DashSet::default()),
+            bus: None,
+            counters: Arc::new(CacheCounters::default()),
+            // Replaced by the configured max age in AuthSessionLayer::with_config.
+            permissions: PermissionCache::new(AuthConfig::<Type>::default().permission_max_age),
             phantom: Default::default(),
         }
     }
+
+    /// Returns the cache's hit, miss, failure and sweep counters with its current size.
+    ///
+    /// # Examples
+    /// ```rust no_run ignore
+    ///  let stats = layer.cache().stats();
+    /// ```
+    ///
+    pub fn stats(&self) -> CacheStats {
+        let counters = &self.counters;
+
+        CacheStats {
+            hits: counters.hits.load(Ordering::Relaxed),
+            misses: counters.misses.load(Ordering::Relaxed),
+            load_failures: counters.load_failures.load(Ordering::Relaxed),
+            evictions: counters.evictions.load(Ordering::Relaxed),
+            sweeps: counters.sweeps.load(Ordering::Relaxed),
+            last_sweep_duration: Duration::from_micros(
+                counters.last_sweep_micros.load(Ordering::Relaxed),
+            ),
+            total_sweep_duration: Duration::from_micros(
+                counters.total_sweep_micros.load(Ordering::Relaxed),
+            ),
+            size: self.inner.len(),
+        }
+    }
+
+    /// Returns the ids of all cached users with the time they expire.
+    ///
+    /// This is a copy taken when called so it does not hold the cache locked.
+    ///
+    /// # Examples
+    /// ```rust no_run ignore
+    ///  for (id, expires) in layer.cache().entries() {
+    ///      println!("{id} expires at {expires}");
+    ///  }
+    /// ```
+    ///
+    pub fn entries(&self) -> impl Iterator<Item = (Type, DateTime<Utc>)> {
+        self.inner
+            .iter()
+            .map(|entry| (entry.key().clone(), entry.expires))
+            .collect::<Vec<_>>()
+            .into_iter()
+    }
+
+    /// Removes all expired users from the cache.
+    ///
+    /// With Degraded::ServeStale users are kept until stale_max_age after they expired.
+    pub(crate) fn sweep(&self, config: &AuthConfig<Type>) {
+        let start = Instant::now();
+        let before = self.inner.len();
+        let now = Utc::now();
+
+        self.inner
+            .retain(|_k, v| v.expires > now || Self::is_servable_stale(v, config, now));
+
+        let micros = start.elapsed().as_micros() as u64;
+        let evicted = before.saturating_sub(self.inner.len()) as u64;
+        let counters = &self.counters;
+
+        telemetry::cache_evictions(evicted);
+        counters.evictions.fetch_add(evicted, Ordering::Relaxed);
+        counters.sweeps.fetch_add(1, Ordering::Relaxed);
+        counters.last_sweep_micros.store(micros, Ordering::Relaxed);
+        counters
+            .total_sweep_micros
+            .fetch_add(micros, Ordering::Relaxed);
+    }
+
+    /// Returns true if the expired user may still be served by Degraded::ServeStale.
+    fn is_servable_stale(
+        user: &AuthUser<User, Type, Pool>,
+        config: &AuthConfig<Type>,
+        now: DateTime<Utc>,
+    ) -> bool {
+        config.degraded == Degraded::ServeStale
+            && user.current_user.is_some()
+            && user.expires + config.stale_max_age > now
+    }
+
+    /// Removes the user from the cache and publishes it to the invalidation bus if one is set.
+    ///
+    /// # Examples
+    /// ```rust no_run ignore
+    ///  cache.clear_user(user.id);
+    /// ```
+    ///
+    pub fn clear_user(&self, id: Type) {
+        let _ = self.inner.remove(&id);
+
+        if let Some(bus) = &self.bus {
+            bus.publish(Invalidation::User(id));
+        }
+    }
+
+    /// Removes all users from the cache and publishes it to the invalidation bus if one is set.
+    ///
+    /// # Examples
+    /// ```rust no_run ignore
+    ///  cache.clear_all();
+    /// ```
+    ///
+    pub fn clear_all(&self) {
+        self.inner.clear();
+
+        if let Some(bus) = &self.bus {
+            bus.publish(Invalidation::All);
+        }
+    }
+
+    /// Returns the permission cache.
+    ///
+    /// # Examples
+    /// ```rust no_run ignore
+    ///  let permissions = cache.permissions();
+    /// ```
+    ///
+    pub fn permissions(&self) -> &PermissionCache<Type> {
+        &self.permissions
+    }
+
+    /// Removes the users permission set and publishes it to the invalidation bus if one is set.
+    ///
+    /// # Examples
+    /// ```rust no_run ignore
+    ///  cache.clear_permissions(user.id);
+    /// ```
+    ///
+    pub fn clear_permissions(&self, id: Type) {
+        self.permissions.clear_user(&id);
+
+        if let Some(bus) = &self.bus {
+            bus.publish(Invalidation::Permissions(id));
+        }
+    }
+
+    /// Removes all permission sets and publishes it to the invalidation bus if one is set.
+    ///
+    /// # Examples
+    /// ```rust no_run ignore
+    ///  cache.clear_all_permissions();
+    /// ```
+    ///
+    pub fn clear_all_permissions(&self) {
+        self.permissions.clear_all();
+
+        if let Some(bus) = &self.bus {
+            bus.publish(Invalidation::AllPermissions);
+        }
+    }
+
+    /// Applies an invalidation received from the bus to the local cache only.
+    pub(crate) fn invalidate(&self, message: Invalidation<Type>) {
+        match message {
+            Invalidation::User(id) => {
+                let _ = self.inner.remove(&id);
+            }
+            Invalidation::All => self.inner.clear(),
+            Invalidation::Permissions(id) => self.permissions.clear_user(&id),
+            Invalidation::AllPermissions => self.permissions.clear_all(),
+        }
+    }
+
+    /// Checks if the user's version still matches current_version.
+    ///
+    /// Users without a version and failed current_version lookups count as current.
+    pub(crate) async fn is_current(id: &Type, user: &User, pool: Option<&Pool>) -> bool {
+        let Some(version) = user.version() else {
+            return true;
+        };
+
+        match User::current_version(id.clone(), pool).await {
+            Ok(current) => current.is_none_or(|current| current == version),
+            Err(err) => {
+                tracing::warn!("current_version failed, keeping cached user. {}", err);
+                true
+            }
+        }
+    }
+
+    /// Stores a user in the cache until expires.
+    pub(crate) fn insert(&self, id: Type, current_user: Option<User>, expires: DateTime<Utc>) {
+        let user = AuthUser::<User, Type, Pool> {
+            current_user,
+            expires,
+            loaded_at: Utc::now(),
+            phantom_pool: Default::default(),
+            phantom_type: Default::default(),
+        };
+
+        self.inner.insert(id, user);
+    }
+
+    /// Calls load_user with the configured timeout, retrying transient errors with backoff.
+    async fn load_with_retries(
+        id: &Type,
+        pool: Option<&Pool>,
+        config: &AuthConfig<Type>,
+    ) -> Result<User, Error> {
+        let mut attempt = 0;
+
+        loop {
+            let result = match config.load_timeout {
+                Some(timeout) => tokio::time::timeout(
+                    timeout.to_std().unwrap_or_default(),
+                    User::load_user(id.clone(), pool),
+                )
+                .await
+                .unwrap_or_else(|_| Err(LoadTimeout.into())),
+                None => User::load_user(id.clone(), pool).await,
+            };
+
+            match result {
+                Err(err)
+                    if attempt < config.load_retries
+                        && User::load_failure(&err) == LoadFailure::Transient =>
+                {
+                    let backoff = config.load_retry_backoff * 2i32.saturating_pow(attempt);
+
+                    tracing::debug!("load_user failed, retrying in {}. {}", backoff, err);
+                    tokio::time::sleep(backoff.to_std().unwrap_or_default()).await;
+                    attempt += 1;
+                }
+                result => return result,
+            }
+        }
+    }
+
+    /// Calls load_user and classifies any error it returns.
+    ///
+    /// Loaded users are cached for max_age and users that are not found are
+    /// cached for not_found_max_age. Transient errors are not cached and are returned,
+    /// unless the config allows serving a stale cached user instead.
+    pub(crate) async fn load_user(
+        &self,
+        id: &Type,
+        pool: Option<&Pool>,
+        config: &AuthConfig<Type>,
+    ) -> Result<Option<User>, Arc<Error>> {
+        let start = Instant::now();
+        let result = Self::load_with_retries(id, pool, config).await;
+        let outcome = match &result {
+            Ok(_) => "ok",
+            Err(err) if User::load_failure(err) == LoadFailure::NotFound => "not_found",
+            Err(_) => "error",
+        };
+
+        telemetry::load_user(start.elapsed(), outcome);
+
+        match result {
+            Ok(current_user) => {
+                if config.cache {
+                    self.insert(
+                        id.clone(),
+                        Some(current_user.clone()),
+                        Utc::now() + config.max_age,
+                    );
+                }
+
+                Ok(Some(current_user))
+            }
+            Err(err) => match User::load_failure(&err) {
+                LoadFailure::NotFound => {
+                    tracing::debug!("load_user did not find the user. {}", err);
+
+                    if config.cache {
+                        self.insert(id.clone(), None, Utc::now() + config.not_found_max_age);
+                    }
+
+                    Ok(None)
+                }
+                LoadFailure::Transient => {
+                    tracing::warn!("load_user failed. {}", err);
+                    self.counters.load_failures.fetch_add(1, Ordering::Relaxed);
+
+                    if config.degraded == Degraded::ServeStale {
+                        let now = Utc::now();
+                        let stale = self
+                            .inner
+                            .get(id)
+                            .filter(|user| {
+                                user.expires > now || Self::is_servable_stale(user, config, now)
+                            })
+                            .and_then(|user| user.current_user.clone());
+
+                        if stale.is_some() {
+                            tracing::warn!("serving stale user after load_user failed.");
+                            return Ok(stale);
+                        }
+                    }
+
+                    Err(Arc::new(err))
+                }
+            },
+        }
+    }
+}
+
+impl<User, Type, Pool> AuthCache<User, Type, Pool>
+where
+    User: Authentication<User, Type, Pool> + Clone + Send + Sync + 'static,
+    Pool: Clone + Send + Sync + fmt::Debug + 'static,
+    Type: Eq
+        + Default
+        + Clone
+        + Send
+        + Sync
+        + Hash
+        + Serialize
+        + DeserializeOwned
+        + fmt::Display
+        + 'static,
+{
+    /// Set's the invalidation bus clears are published to, and spawns a task applying
+    /// the clears other app instances publish to this cache.
+    ///
+    /// Must be called within a tokio runtime.
+    ///
+    /// # Examples
+    /// ```rust no_run ignore
+    ///  cache.set_bus(Arc::new(BroadcastBus::new(64)));
+    /// ```
+    ///
+    pub(crate) fn set_bus(&mut self, bus: Arc<dyn InvalidationBus<Type>>) {
+        let mut messages = bus.subscribe();
+        let cache = self.clone();
+
+        tokio::spawn(async move {
+            while let Some(message) = messages.next().await {
+                cache.invalidate(message);
+            }
+
+            tracing::warn!("invalidation bus subscription closed.");
+        });
+
+        self.bus = Some(bus);
+    }
+
+    /// Looks up a user in the cache, extending their expiry by max_age.
+    ///
+    /// Returns None when the user needs to be loaded, which includes not found
+    /// entries that outlived their not_found_max_age. Starts a refresh_ahead when
+    /// the cached copy is close to its max_age.
+    ///
+    /// # Examples
+    /// ```rust no_run ignore
+    ///  let user = cache.get_user(&id, &pool, &config);
+    /// ```
+    ///
+    pub(crate) fn get_user(
+        &self,
+        id: &Type,
+        pool: &Option<Pool>,
+        config: &AuthConfig<Type>,
+    ) -> Option<Option<User>> {
+        let mut user = self.inner.get_mut(id)?;
+        let now = Utc::now();
+
+        // Expired users are only kept to be served by Degraded::ServeStale if loading fails.
+        if user.expires <= now {
+            return None;
+        }
+
+        if user.current_user.is_none() {
+            return Some(None);
+        }
+
+        // Checked against when the user was loaded, as the expiry below slides on every hit
+        // and would otherwise never come within the window for users seen regularly.
+        let refresh = config
+            .refresh_ahead
+            .is_some_and(|window| user.loaded_at() + config.max_age - now <= window);
+
+        user.expires = now + config.max_age;
+        let current_user = user.current_user.clone();
+        drop(user);
+
+        if refresh {
+            self.refresh_ahead(id.clone(), pool.clone(), config);
+        }
+
+        Some(current_user)
+    }
+
+    /// Returns the user from the cache, or calls load_user if they are not cached.
+    ///
+    /// The default id is never loaded. Records auth.cache_hit and auth.load_ms on the current span.
+    pub(crate) async fn get_or_load_user(
+        &self,
+        id: &Type,
+        pool: &Option<Pool>,
+        config: &AuthConfig<Type>,
+    ) -> Result<Option<User>, Arc<Error>> {
+        if *id == Type::default() {
+            return Ok(None);
+        }
+
+        let span = tracing::Span::current();
+
+        if config.cache {
+            if let Some(current_user) = self.get_current_user(id, pool, config).await {
+                tracing::debug!("user id: {} found in cache", id);
+                span.record("auth.cache_hit", true);
+                return Ok(current_user);
+            }
+        }
+
+        tracing::debug!("loading user id: {} from load_user", id);
+        span.record("auth.cache_hit", false);
+
+        let start = Instant::now();
+        let current_user = self.load_user(id, pool.as_ref(), config).await;
+
+        span.record("auth.load_ms", start.elapsed().as_millis() as u64);
+        current_user
+    }
+
+    /// Looks up a user in the cache like get_user, reloading them if their version changed.
+    pub(crate) async fn get_current_user(
+        &self,
+        id: &Type,
+        pool: &Option<Pool>,
+        config: &AuthConfig<Type>,
+    ) -> Option<Option<User>> {
+        let Some(current_user) = self.get_user(id, pool, config) else {
+            self.counters.misses.fetch_add(1, Ordering::Relaxed);
+            telemetry::cache_lookup(false);
+            return None;
+        };
+
+        if let Some(user) = &current_user {
+            if !Self::is_current(id, user, pool.as_ref()).await {
+                tracing::debug!("user id: {} version changed, reloading", id);
+                self.counters.misses.fetch_add(1, Ordering::Relaxed);
+                telemetry::cache_lookup(false);
+                return None;
+            }
+        }
+
+        self.counters.hits.fetch_add(1, Ordering::Relaxed);
+        telemetry::cache_lookup(true);
+        Some(current_user)
+    }
+
+    /// Reloads a cached user in a spawned task while the stale copy keeps being served.
+    ///
+    /// Only one refresh per user id runs at a time. If load_user fails with a transient
+    /// error the stale user is kept, and if the entry was cleared while loading the result is dropped.
+    pub(crate) fn refresh_ahead(&self, id: Type, pool: Option<Pool>, config: &AuthConfig<Type>) {
+        if !self.refreshing.insert(id.clone()) {
+            return;
+        }
+
+        let cache = self.clone();
+        let config = config.clone();
+
+        tokio::spawn(async move {
+            tracing::debug!("refreshing user id: {} ahead of cache expiry", id);
+
+            match Self::load_with_retries(&id, pool.as_ref(), &config).await {
+                Ok(current_user) => {
+                    if let Some(mut user) = cache.inner.get_mut(&id) {
+                        user.current_user = Some(current_user);
+                        user.expires = Utc::now() + config.max_age;
+                        user.loaded_at = Utc::now();
+                    }
+                }
+                Err(err) => match User::load_failure(&err) {
+                    LoadFailure::NotFound => {
+                        if let Some(mut user) = cache.inner.get_mut(&id) {
+                            user.current_user = None;
+                            user.expires = Utc::now() + config.not_found_max_age;
+                            user.loaded_at = Utc::now();
+                        }
+                    }
+                    LoadFailure::Transient => {
+                        cache.counters.load_failures.fetch_add(1, Ordering::Relaxed);
+                        tracing::warn!(
+                            "refresh of user id: {} failed, serving stale user. {}",
+                            id,
+                            err
+                        );
+                    }
+                },
+            }
+
+            cache.refreshing.remove(&id);
+        });
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::BroadcastBus;
+    use async_trait::async_trait;
+    use std::sync::atomic::AtomicUsize;
+
+    static LOADS: AtomicUsize = AtomicUsize::new(0);
+
+    #[derive(Debug, Clone, PartialEq)]
+    struct User {
+        id: i64,
+        generation: usize,
+    }
+
+    #[async_trait]
+    impl Authentication<User, i64, ()> for User {
+        async fn load_user(userid: i64, _pool: Option<&()>) -> Result<User, Error> {
+            match userid {
+                1 => Ok(User {
+                    id: userid,
+                    generation: LOADS.fetch_add(1, Ordering::SeqCst) + 1,
+                }),
+                _ => Err(anyhow::anyhow!("database is down")),
+            }
+        }
+
+        fn is_authenticated(&self) -> bool {
+            true
+        }
+
+        fn is_active(&self) -> bool {
+            true
+        }
+
+        fn is_anonymous(&self) -> bool {
+            false
+        }
+    }
+
+    fn cache() -> AuthCache<User, i64, ()> {
+        AuthCache::new(Utc::now())
+    }
+
+    #[tokio::test]
+    async fn refresh_ahead_reloads_regularly_used_users() {
+        let cache = cache();
+        let config = AuthConfig::<i64>::default()
+            .with_max_age(chrono::Duration::try_hours(1).unwrap())
+            .with_refresh_ahead(Some(chrono::Duration::try_minutes(10).unwrap()));
+
+        let loaded = cache.load_user(&1, None, &config).await.unwrap().unwrap();
+
+        // Regular hits slide the expiry but must not push back the refresh.
+        for _ in 0..3 {
+            assert_eq!(
+                cache.get_user(&1, &None, &config),
+                Some(Some(loaded.clone()))
+            );
+        }
+
+        // 55 minutes later the loaded copy is within the 10 minute window.
+        cache.inner.get_mut(&1).unwrap().loaded_at -= chrono::Duration::try_minutes(55).unwrap();
+
+        assert_eq!(
+            cache.get_user(&1, &None, &config),
+            Some(Some(loaded.clone()))
+        );
+
+        for _ in 0..100 {
+            if !cache.refreshing.contains(&1) {
+                break;
+            }
+
+            tokio::time::sleep(Duration::from_millis(5)).await;
+        }
+
+        let refreshed = cache.inner.get(&1).unwrap().current_user.clone().unwrap();
+        assert_eq!(refreshed.id, 1);
+        assert!(refreshed.generation > loaded.generation);
+        assert!(
+            cache.inner.get(&1).unwrap().loaded_at
+                > Utc::now() - chrono::Duration::try_minutes(1).unwrap()
+        );
+    }
+
+    #[tokio::test]
+    async fn serve_stale_serves_expired_users_when_loading_fails() {
+        let cache = cache();
+        let config = AuthConfig::<i64>::default()
+            .with_degraded(Degraded::ServeStale)
+            .with_stale_max_age(chrono::Duration::try_hours(1).unwrap());
+        let stale = User {
+            id: 2,
+            generation: 0,
+        };
+
+        // Expired ten minutes ago and not used since.
+        cache.insert(
+            2,
+            Some(stale.clone()),
+            Utc::now() - chrono::Duration::try_minutes(10).unwrap(),
+        );
+        cache.sweep(&config);
+
+        assert_eq!(cache.get_user(&2, &None, &config), None);
+        assert_eq!(
+            cache.get_or_load_user(&2, &None, &config).await.unwrap(),
+            Some(stale)
+        );
+
+        // Past the stale max age it is swept and the load error is returned.
+        cache.inner.get_mut(&2).unwrap().expires -= chrono::Duration::try_hours(1).unwrap();
+        cache.sweep(&config);
+
+        assert!(cache.inner.get(&2).is_none());
+        assert!(cache.get_or_load_user(&2, &None, &config).await.is_err());
+    }
+
+    #[tokio::test]
+    async fn expired_users_are_not_served_without_serve_stale() {
+        let cache = cache();
+        let config = AuthConfig::<i64>::default();
+
+        cache.insert(
+            2,
+            Some(User {
+                id: 2,
+                generation: 0,
+            }),
+            Utc::now() - chrono::Duration::try_minutes(10).unwrap(),
+        );
+
+        assert!(cache.get_or_load_user(&2, &None, &config).await.is_err());
+
+        cache.sweep(&config);
+        assert!(cache.inner.get(&2).is_none());
+    }
+
+    #[tokio::test]
+    async fn lagging_behind_the_bus_clears_users_and_permissions() {
+        let mut cache = cache();
+        let bus = Arc::new(BroadcastBus::<i64>::new(1));
+        cache.set_bus(bus.clone());
+
+        cache.insert(
+            1,
+            Some(User {
+                id: 1,
+                generation: 0,
+            }),
+            Utc::now() + chrono::Duration::try_hours(1).unwrap(),
+        );
+        cache.permissions.inner.insert(
+            1,
+            crate::permissions::CachedPermissions {
+                permissions: Arc::new(["read".to_string()].into()),
+                expires: Utc::now() + chrono::Duration::try_hours(1).unwrap(),
+            },
+        );
+
+        // The subscriber does not run before this test yields, so it falls behind.
+        bus.publish(Invalidation::User(2));
+        bus.publish(Invalidation::User(3));
+
+        for _ in 0..100 {
+            if cache.inner.is_empty() && cache.permissions.inner.is_empty() {
+                break;
+            }
+
+            tokio::time::sleep(Duration::from_millis(5)).await;
+        }
+
+        assert!(cache.inner.is_empty());
+        assert!(cache.permissions.inner.is_empty());
+    }
+
+    #[tokio::test]
+    async fn users_are_reloaded_when_their_version_changes() {
+        use crate::testing::{loads, set_version, User};
+
+        async fn version(cache: &AuthCache<User, i64, ()>) -> Option<i64> {
+            let config = AuthConfig::<i64>::default();
+            let user = cache.get_or_load_user(&30, &None, &config).await;
+            user.unwrap().unwrap().version
+        }
+
+        let cache = AuthCache::<User, i64, ()>::new(Utc::now());
+
+        set_version(30, 1);
+        assert_eq!(version(&cache).await, Some(1));
+        assert_eq!(version(&cache).await, Some(1));
+        assert_eq!(loads(30), 1);
+
+        set_version(30, 2);
+        assert_eq!(version(&cache).await, Some(2));
+        assert_eq!(loads(30), 2);
+    }
+
+    #[tokio::test]
+    async fn stats_count_lookups_failures_and_evictions() {
+        use crate::testing::{User, DOWN};
+
+        let cache = AuthCache::<User, i64, ()>::new(Utc::now());
+        let config = AuthConfig::<i64>::default();
+
+        for _ in 0..2 {
+            assert!(cache.get_or_load_user(&40, &None, &config).await.is_ok());
+        }
+
+        assert!(cache
+            .get_or_load_user(&(DOWN.start + 40), &None, &config)
+            .await
+            .is_err());
+
+        let stats = cache.stats();
+        assert_eq!((stats.hits, stats.misses, stats.load_failures), (1, 2, 1));
+        assert_eq!(stats.size, 1);
+        assert_eq!(cache.entries().map(|(id, _)| id).collect::<Vec<_>>(), [40]);
+
+        cache.inner.get_mut(&40).unwrap().expires = Utc::now();
+        cache.sweep(&config);
+
+        let stats = cache.stats();
+        assert_eq!((stats.evictions, stats.sweeps, stats.size), (1, 1, 0));
+    }
 }
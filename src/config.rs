@@ -3,6 +3,22 @@ use serde::{de::DeserializeOwned, Serialize};
 use std::borrow::Cow;
 use std::hash::Hash;
 
+/// What the Auth service does when load_user fails with a transient error,
+/// after any retries.
+///
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum Degraded {
+    /// Treat the user as not loaded and set the AuthSession's load_error.
+    #[default]
+    Anonymous,
+    /// Serve the user still held in the cache if there is one, otherwise act like Anonymous.
+    /// Expired users are kept for AuthConfig::with_stale_max_age to be served.
+    ServeStale,
+    /// Respond with 503 Service Unavailable without calling the inner service.
+    /// In lazy mode the error is returned from `AuthSession::user` instead.
+    Reject,
+}
+
 /// Configuration for how the Auth service is used.
 ///
 /// # Examples
@@ -25,6 +41,39 @@ where
     pub(crate) session_id: Cow<'static, str>,
     /// Age the cache is allowed to live for if no visits are made.
     pub(crate) max_age: Duration,
+    /// Age a user that load_user could not find is cached for.
+    pub(crate) not_found_max_age: Duration,
+    /// Age a users resolved permission set is cached for.
+    pub(crate) permission_max_age: Duration,
+    /// Max time a single load_user call may take before it fails with LoadTimeout.
+    pub(crate) load_timeout: Option<Duration>,
+    /// How often a load_user call that failed with a transient error is retried.
+    pub(crate) load_retries: u32,
+    /// Delay before the first retry, doubled for every retry after.
+    pub(crate) load_retry_backoff: Duration,
+    /// What to do when load_user still fails with a transient error.
+    pub(crate) degraded: Degraded,
+    /// How long expired users are kept for Degraded::ServeStale.
+    pub(crate) stale_max_age: Duration,
+    /// Only load the user when AuthSession::user is first called.
+    pub(crate) lazy_load: bool,
+    /// Window before a cached users expiry in which they are reloaded in the background.
+    pub(crate) refresh_ahead: Option<Duration>,
+    /// Header set by a trusted proxy to read the client ip from.
+    pub(crate) client_ip_header: Option<Cow<'static, str>>,
+    /// How many proxies append to the client ip header, counted from its right.
+    pub(crate) trusted_proxies: usize,
+    /// Hasher used by login_with_password.
+    #[cfg(feature = "credentials")]
+    pub(crate) password_hashing: crate::PasswordHashing,
+    /// Limits failed login_with_password and Basic auth attempts.
+    pub(crate) login_limiter: Option<crate::LoginLimiter>,
+    /// Puts users into a pending state on login_user until a second factor is verified.
+    #[cfg(feature = "totp")]
+    pub(crate) second_factor: bool,
+    /// How long a login may wait for its second factor.
+    #[cfg(feature = "totp")]
+    pub(crate) second_factor_timeout: Duration,
 }
 
 impl<Type> std::fmt::Debug for AuthConfig<Type>
@@ -36,7 +85,18 @@ where
             .field("cache", &self.cache)
             .field("session_id", &self.session_id)
             .field("max_age", &self.max_age)
-            .finish()
+            .field("not_found_max_age", &self.not_found_max_age)
+            .field("permission_max_age", &self.permission_max_age)
+            .field("load_timeout", &self.load_timeout)
+            .field("load_retries", &self.load_retries)
+            .field("load_retry_backoff", &self.load_retry_backoff)
+            .field("degraded", &self.degraded)
+            .field("stale_max_age", &self.stale_max_age)
+            .field("lazy_load", &self.lazy_load)
+            .field("refresh_ahead", &self.refresh_ahead)
+            .field("client_ip_header", &self.client_ip_header)
+            .field("trusted_proxies", &self.trusted_proxies)
+            .finish_non_exhaustive()
     }
 }
 
@@ -101,6 +161,272 @@ where
         self
     }
 
+    /// Set's how long a user that load_user could not find is cached for.
+    ///
+    /// This only applies to errors classified as [`LoadFailure::NotFound`](crate::LoadFailure).
+    /// Transient errors are never cached. Unlike found users this age is not extended per request.
+    ///
+    /// # Examples
+    /// ```rust
+    /// use axum_session_auth::AuthConfig;
+    /// use chrono::Duration;
+    ///
+    /// let config = AuthConfig::<i64>::default().with_not_found_max_age(Duration::seconds(30));
+    /// ```
+    ///
+    #[must_use]
+    pub fn with_not_found_max_age(mut self, time: Duration) -> Self {
+        self.not_found_max_age = time;
+        self
+    }
+
+    /// Set's how long a users resolved permission set is cached for.
+    ///
+    /// Permission sets come from `HasPermission::load_permissions` and are cached
+    /// even if user caching is disabled. Unlike users this age is not extended per request.
+    ///
+    /// # Examples
+    /// ```rust
+    /// use axum_session_auth::AuthConfig;
+    /// use chrono::Duration;
+    ///
+    /// let config = AuthConfig::<i64>::default().with_permission_max_age(Duration::minutes(10));
+    /// ```
+    ///
+    #[must_use]
+    pub fn with_permission_max_age(mut self, time: Duration) -> Self {
+        self.permission_max_age = time;
+        self
+    }
+
+    /// Set's the max time a single load_user call may take.
+    ///
+    /// Calls taking longer fail with a [`LoadTimeout`](crate::LoadTimeout) error. None disables the timeout.
+    ///
+    /// # Examples
+    /// ```rust
+    /// use axum_session_auth::AuthConfig;
+    /// use chrono::Duration;
+    ///
+    /// let config = AuthConfig::<i64>::default().with_load_timeout(Some(Duration::seconds(2)));
+    /// ```
+    ///
+    #[must_use]
+    pub fn with_load_timeout(mut self, timeout: Option<Duration>) -> Self {
+        self.load_timeout = timeout;
+        self
+    }
+
+    /// Set's how often load_user is retried after a transient error.
+    ///
+    /// The first retry waits for backoff, every retry after waits twice as long as the last.
+    ///
+    /// # Examples
+    /// ```rust
+    /// use axum_session_auth::AuthConfig;
+    /// use chrono::Duration;
+    ///
+    /// let config = AuthConfig::<i64>::default().with_load_retries(2, Duration::milliseconds(50));
+    /// ```
+    ///
+    #[must_use]
+    pub fn with_load_retries(mut self, retries: u32, backoff: Duration) -> Self {
+        self.load_retries = retries;
+        self.load_retry_backoff = backoff;
+        self
+    }
+
+    /// Set's what happens when load_user still fails with a transient error after retrying.
+    ///
+    /// # Examples
+    /// ```rust
+    /// use axum_session_auth::{AuthConfig, Degraded};
+    ///
+    /// let config = AuthConfig::<i64>::default().with_degraded(Degraded::Reject);
+    /// ```
+    ///
+    #[must_use]
+    pub fn with_degraded(mut self, degraded: Degraded) -> Self {
+        self.degraded = degraded;
+        self
+    }
+
+    /// Set's how long users are kept in the cache after they expired, to be served by
+    /// Degraded::ServeStale when load_user fails. Expired users are never served otherwise.
+    /// Defaults to 1 hour.
+    ///
+    /// # Examples
+    /// ```rust
+    /// use axum_session_auth::{AuthConfig, Degraded};
+    /// use chrono::Duration;
+    ///
+    /// let config = AuthConfig::<i64>::default()
+    ///     .with_degraded(Degraded::ServeStale)
+    ///     .with_stale_max_age(Duration::try_hours(6).unwrap());
+    /// ```
+    ///
+    #[must_use]
+    pub fn with_stale_max_age(mut self, time: Duration) -> Self {
+        self.stale_max_age = time;
+        self
+    }
+
+    /// Sets the auth session to only load the user when they are used.
+    ///
+    /// When enabled the service only reads the user id from the session and the user is
+    /// loaded (and cached) on the first call to `AuthSession::user`. current_user and
+    /// load_error stay None, use user() instead. Clones of the AuthSession within a request
+    /// share the loaded user.
+    ///
+    /// # Examples
+    /// ```rust
+    /// use axum_session_auth::AuthConfig;
+    ///
+    /// let config = AuthConfig::<i64>::default().with_lazy_load(true);
+    /// ```
+    ///
+    #[must_use]
+    pub fn with_lazy_load(mut self, lazy_load: bool) -> Self {
+        self.lazy_load = lazy_load;
+        self
+    }
+
+    /// Set's the refresh-ahead window of the user cache.
+    ///
+    /// When a cached user is requested and was loaded longer than max_age minus this window
+    /// ago, the cached user is returned right away and load_user is called in a spawned task
+    /// to refresh it. If the refresh fails the stale user is kept. None disables refresh-ahead.
+    ///
+    /// # Examples
+    /// ```rust
+    /// use axum_session_auth::AuthConfig;
+    /// use chrono::Duration;
+    ///
+    /// let config = AuthConfig::<i64>::default().with_refresh_ahead(Some(Duration::minutes(30)));
+    /// ```
+    ///
+    #[must_use]
+    pub fn with_refresh_ahead(mut self, window: Option<Duration>) -> Self {
+        self.refresh_ahead = window;
+        self
+    }
+
+    /// Set's the header the client ip is read from, for RequestInfo and audit events.
+    ///
+    /// Proxies append the address they received the request from, so the rightmost
+    /// address is used, or the one added by the outermost proxy set with
+    /// with_trusted_proxies. Addresses left of it can be sent by the client and are
+    /// ignored. Defaults to None.
+    ///
+    /// # Examples
+    /// ```rust
+    /// use axum_session_auth::AuthConfig;
+    ///
+    /// let config = AuthConfig::<i64>::default().with_client_ip_header("x-forwarded-for");
+    /// ```
+    ///
+    #[must_use]
+    pub fn with_client_ip_header(mut self, header: impl Into<Cow<'static, str>>) -> Self {
+        self.client_ip_header = Some(header.into());
+        self
+    }
+
+    /// Set's how many trusted proxies append to the client ip header. Defaults to 1.
+    ///
+    /// The address added by the outermost of them is used, so with a CDN in front of a
+    /// load balancer set this to 2. Requests with fewer addresses get no client ip.
+    ///
+    /// # Examples
+    /// ```rust
+    /// use axum_session_auth::AuthConfig;
+    ///
+    /// let config = AuthConfig::<i64>::default()
+    ///     .with_client_ip_header("x-forwarded-for")
+    ///     .with_trusted_proxies(2);
+    /// ```
+    ///
+    #[must_use]
+    pub fn with_trusted_proxies(mut self, proxies: usize) -> Self {
+        self.trusted_proxies = proxies.max(1);
+        self
+    }
+
+    /// Set's the Argon2id parameters login_with_password hashes with.
+    ///
+    /// Users whose stored hash used other parameters get it rehashed on their next login.
+    ///
+    /// # Examples
+    /// ```rust
+    /// use axum_session_auth::{AuthConfig, PasswordHashing};
+    ///
+    /// let config = AuthConfig::<i64>::default()
+    ///     .with_password_hashing(PasswordHashing::new(64 * 1024, 3, 1).unwrap());
+    /// ```
+    ///
+    #[cfg(feature = "credentials")]
+    #[must_use]
+    pub fn with_password_hashing(mut self, hashing: crate::PasswordHashing) -> Self {
+        self.password_hashing = hashing;
+        self
+    }
+
+    /// Set's the LoginLimiter login_with_password and BasicAuth use against brute forcing.
+    ///
+    /// Both count failures in the same limiter, so they share one budget per username and
+    /// client ip. None disables limiting. Defaults to LoginLimiter::default().
+    ///
+    /// # Examples
+    /// ```rust
+    /// use axum_session_auth::{AuthConfig, LoginLimiter};
+    ///
+    /// let config = AuthConfig::<i64>::default().with_login_limiter(Some(LoginLimiter::default()));
+    /// ```
+    ///
+    #[must_use]
+    pub fn with_login_limiter(mut self, limiter: Option<crate::LoginLimiter>) -> Self {
+        self.login_limiter = limiter;
+        self
+    }
+
+    /// Set's if login_user waits for a second factor before the user is logged in. Defaults to false.
+    ///
+    /// Authenticators asking for a login, like BasicAuthMode::Session, then only
+    /// authenticate their own request and never log the user into the session.
+    /// Wrong codes are counted against the user id in the LoginLimiter, so logging in
+    /// with the password again does not reset them.
+    ///
+    /// # Examples
+    /// ```rust
+    /// use axum_session_auth::AuthConfig;
+    ///
+    /// let config = AuthConfig::<i64>::default().with_second_factor(true);
+    /// ```
+    ///
+    #[cfg(feature = "totp")]
+    #[must_use]
+    pub fn with_second_factor(mut self, second_factor: bool) -> Self {
+        self.second_factor = second_factor;
+        self
+    }
+
+    /// Set's how long a pending login waits for its second factor. Defaults to 5 minutes.
+    ///
+    /// # Examples
+    /// ```rust
+    /// use axum_session_auth::AuthConfig;
+    /// use chrono::Duration;
+    ///
+    /// let config = AuthConfig::<i64>::default()
+    ///     .with_second_factor_timeout(Duration::try_minutes(10).unwrap());
+    /// ```
+    ///
+    #[cfg(feature = "totp")]
+    #[must_use]
+    pub fn with_second_factor_timeout(mut self, timeout: Duration) -> Self {
+        self.second_factor_timeout = timeout;
+        self
+    }
+
     /// Set's the auth session's token for session storage.
     ///
     /// # Examples
@@ -128,6 +454,24 @@ where
             session_id: "user_auth_session_id".into(),
             max_age: Duration::try_hours(6).unwrap_or_default(),
             anonymous_user_id: None,
+            load_timeout: None,
+            load_retries: 0,
+            load_retry_backoff: Duration::try_milliseconds(100).unwrap_or_default(),
+            degraded: Degraded::Anonymous,
+            stale_max_age: Duration::try_hours(1).unwrap_or_default(),
+            lazy_load: false,
+            not_found_max_age: Duration::try_minutes(1).unwrap_or_default(),
+            permission_max_age: Duration::try_minutes(5).unwrap_or_default(),
+            refresh_ahead: None,
+            client_ip_header: None,
+            trusted_proxies: 1,
+            #[cfg(feature = "credentials")]
+            password_hashing: crate::PasswordHashing::default(),
+            login_limiter: Some(crate::LoginLimiter::default()),
+            #[cfg(feature = "totp")]
+            second_factor: false,
+            #[cfg(feature = "totp")]
+            second_factor_timeout: Duration::try_minutes(5).unwrap_or_default(),
         }
     }
 }
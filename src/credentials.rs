@@ -0,0 +1,185 @@
+use anyhow::Error;
+use argon2::{
+    password_hash::{rand_core::OsRng, PasswordHash, PasswordHasher, PasswordVerifier, SaltString},
+    Algorithm, Argon2, Params, Version,
+};
+use async_trait::async_trait;
+use std::fmt;
+
+/// A users id and stored password hash, as returned by CredentialStore::lookup.
+///
+#[derive(Clone)]
+pub struct Credentials<Type> {
+    pub id: Type,
+    /// PHC string of the password hash, like `$argon2id$v=19$m=19456,t=2,p=1$...`.
+    pub hash: String,
+}
+
+impl<Type: fmt::Debug> fmt::Debug for Credentials<Type> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("Credentials")
+            .field("id", &self.id)
+            .finish_non_exhaustive()
+    }
+}
+
+/// Where login_with_password looks up users by username.
+///
+/// # Examples
+/// ```rust no_run ignore
+/// #[async_trait]
+/// impl CredentialStore<i64, PgPool> for Users {
+///     async fn lookup(&self, username: &str, pool: Option<&PgPool>) -> Result<Option<Credentials<i64>>, anyhow::Error> {
+///         let row: Option<(i64, String)> =
+///             sqlx::query_as("SELECT id, password_hash FROM users WHERE username = $1")
+///                 .bind(username)
+///                 .fetch_optional(pool.unwrap())
+///                 .await?;
+///
+///         Ok(row.map(|(id, hash)| Credentials { id, hash }))
+///     }
+///
+///     async fn update_hash(&self, id: &i64, hash: String, pool: Option<&PgPool>) -> Result<(), anyhow::Error> {
+///         sqlx::query("UPDATE users SET password_hash = $1 WHERE id = $2")
+///             .bind(hash)
+///             .bind(id)
+///             .execute(pool.unwrap())
+///             .await?;
+///
+///         Ok(())
+///     }
+/// }
+/// ```
+///
+#[async_trait]
+pub trait CredentialStore<Type, Pool>: Send + Sync
+where
+    Type: Send + Sync,
+    Pool: Send + Sync,
+{
+    /// Returns the id and password hash of the user with this username, or None if there is none.
+    async fn lookup(
+        &self,
+        username: &str,
+        pool: Option<&Pool>,
+    ) -> Result<Option<Credentials<Type>>, Error>;
+
+    /// Stores a new hash for the user, called when their hash used outdated parameters.
+    async fn update_hash(&self, id: &Type, hash: String, pool: Option<&Pool>) -> Result<(), Error>;
+}
+
+/// Why login_with_password did not log the user in.
+///
+#[derive(Debug)]
+pub enum LoginError {
+    /// The username does not exist or the password is wrong.
+    InvalidCredentials,
+    /// Too many failed attempts, the next attempt is allowed after retry_after.
+    LockedOut { retry_after: chrono::Duration },
+    /// The CredentialStore or the LoginLimiter's AttemptStore failed.
+    Store(Error),
+    /// The stored hash could not be parsed or hashing failed.
+    Hash(argon2::password_hash::Error),
+}
+
+impl fmt::Display for LoginError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            LoginError::InvalidCredentials => f.write_str("invalid username or password"),
+            LoginError::LockedOut { retry_after } => write!(
+                f,
+                "too many failed login attempts, retry in {} seconds",
+                retry_after.num_seconds()
+            ),
+            LoginError::Store(err) => write!(f, "credential store failed: {}", err),
+            LoginError::Hash(err) => write!(f, "password hashing failed: {}", err),
+        }
+    }
+}
+
+impl std::error::Error for LoginError {}
+
+impl From<argon2::password_hash::Error> for LoginError {
+    fn from(err: argon2::password_hash::Error) -> Self {
+        LoginError::Hash(err)
+    }
+}
+
+/// Argon2id password hasher with configurable cost parameters.
+///
+/// The defaults are the argon2 crate's defaults, which follow the OWASP recommendation.
+///
+/// # Examples
+/// ```rust
+/// use axum_session_auth::PasswordHashing;
+///
+/// let hashing = PasswordHashing::new(19 * 1024, 2, 1).unwrap();
+/// let hash = hashing.hash("hunter2").unwrap();
+///
+/// assert!(hashing.verify("hunter2", &hash).unwrap());
+/// assert!(!hashing.needs_rehash(&hash));
+/// ```
+///
+#[derive(Debug, Clone, Default)]
+pub struct PasswordHashing {
+    params: Params,
+}
+
+impl PasswordHashing {
+    /// Creates a hasher using m_cost KiB of memory, t_cost iterations and p_cost lanes.
+    pub fn new(m_cost: u32, t_cost: u32, p_cost: u32) -> Result<Self, argon2::Error> {
+        Ok(Self {
+            params: Params::new(m_cost, t_cost, p_cost, None)?,
+        })
+    }
+
+    fn argon2(&self) -> Argon2<'static> {
+        Argon2::new(Algorithm::Argon2id, Version::V0x13, self.params.clone())
+    }
+
+    /// Hashes the password with a new random salt, returning a PHC string.
+    pub fn hash(&self, password: &str) -> Result<String, argon2::password_hash::Error> {
+        let salt = SaltString::generate(&mut OsRng);
+
+        Ok(self
+            .argon2()
+            .hash_password(password.as_bytes(), &salt)?
+            .to_string())
+    }
+
+    /// Checks the password against a PHC string.
+    ///
+    /// The hash is checked with the parameters stored in it, so hashes made
+    /// with older parameters still verify.
+    pub fn verify(&self, password: &str, hash: &str) -> Result<bool, argon2::password_hash::Error> {
+        let hash = PasswordHash::new(hash)?;
+
+        match self.argon2().verify_password(password.as_bytes(), &hash) {
+            Ok(()) => Ok(true),
+            Err(argon2::password_hash::Error::Password) => Ok(false),
+            Err(err) => Err(err),
+        }
+    }
+
+    /// Returns true if the hash was not made with Argon2id and this hasher's parameters.
+    pub fn needs_rehash(&self, hash: &str) -> bool {
+        let Ok(hash) = PasswordHash::new(hash) else {
+            return true;
+        };
+
+        if hash.algorithm != Algorithm::Argon2id.ident()
+            || hash.version != Some(Version::V0x13.into())
+        {
+            return true;
+        }
+
+        match Params::try_from(&hash) {
+            Ok(params) => {
+                params.m_cost() != self.params.m_cost()
+                    || params.t_cost() != self.params.t_cost()
+                    || params.p_cost() != self.params.p_cost()
+            }
+            Err(_) => true,
+        }
+    }
+}
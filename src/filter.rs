@@ -0,0 +1,179 @@
+use http::{Method, Uri};
+use std::{borrow::Cow, fmt, sync::Arc};
+
+/// Path pattern used to include or exclude requests from the AuthSessionService.
+///
+/// A prefix matches the path itself and everything below it, so `/assets` matches
+/// `/assets` and `/assets/app.js` but not `/assets2`. A glob matches the whole path where
+/// `*` matches within a single path segment, `**` matches any number of segments and `?`
+/// matches a single character.
+///
+/// # Examples
+/// ```rust
+/// use axum_session_auth::PathPattern;
+///
+/// assert!(PathPattern::prefix("/assets").matches("/assets/app.js"));
+/// assert!(PathPattern::glob("/static/**/*.css").matches("/static/css/site.css"));
+/// // Patterns containing `*` or `?` are globs, everything else is a prefix.
+/// assert!(PathPattern::from("/healthz").matches("/healthz"));
+/// ```
+///
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum PathPattern {
+    Prefix(Cow<'static, str>),
+    Glob(Cow<'static, str>),
+}
+
+impl PathPattern {
+    /// Creates a prefix pattern.
+    pub fn prefix(pattern: impl Into<Cow<'static, str>>) -> Self {
+        PathPattern::Prefix(pattern.into())
+    }
+
+    /// Creates a glob pattern.
+    pub fn glob(pattern: impl Into<Cow<'static, str>>) -> Self {
+        PathPattern::Glob(pattern.into())
+    }
+
+    /// Checks if the request path matches this pattern.
+    pub fn matches(&self, path: &str) -> bool {
+        match self {
+            PathPattern::Prefix(prefix) => match path.strip_prefix(prefix.as_ref()) {
+                Some(rest) => rest.is_empty() || prefix.ends_with('/') || rest.starts_with('/'),
+                None => false,
+            },
+            PathPattern::Glob(glob) => glob_matches(glob.as_bytes(), path.as_bytes()),
+        }
+    }
+}
+
+impl From<Cow<'static, str>> for PathPattern {
+    fn from(pattern: Cow<'static, str>) -> Self {
+        if pattern.contains(['*', '?']) {
+            PathPattern::Glob(pattern)
+        } else {
+            PathPattern::Prefix(pattern)
+        }
+    }
+}
+
+impl From<&'static str> for PathPattern {
+    fn from(pattern: &'static str) -> Self {
+        Cow::Borrowed(pattern).into()
+    }
+}
+
+impl From<String> for PathPattern {
+    fn from(pattern: String) -> Self {
+        Cow::<'static, str>::Owned(pattern).into()
+    }
+}
+
+fn glob_matches(glob: &[u8], path: &[u8]) -> bool {
+    match glob {
+        [] => path.is_empty(),
+        [b'*', b'*', rest @ ..] => {
+            // `**/` can also match no segments at all.
+            let rest_without_slash = rest.strip_prefix(b"/").unwrap_or(rest);
+
+            glob_matches(rest_without_slash, path)
+                || (0..=path.len()).any(|i| glob_matches(rest, &path[i..]))
+        }
+        [b'*', rest @ ..] => {
+            let segment_end = path.iter().position(|c| *c == b'/').unwrap_or(path.len());
+
+            (0..=segment_end).any(|i| glob_matches(rest, &path[i..]))
+        }
+        [b'?', rest @ ..] => match path {
+            [c, path @ ..] if *c != b'/' => glob_matches(rest, path),
+            _ => false,
+        },
+        [g, rest @ ..] => match path {
+            [c, path @ ..] if c == g => glob_matches(rest, path),
+            _ => false,
+        },
+    }
+}
+
+/// Predicate that returns true for requests the AuthSessionService should skip.
+pub(crate) type SkipPredicate = Arc<dyn Fn(&Method, &Uri) -> bool + Send + Sync>;
+
+/// Decides which requests are passed straight to the inner service.
+#[derive(Clone, Default)]
+pub(crate) struct PathFilter {
+    pub(crate) include: Vec<PathPattern>,
+    pub(crate) exclude: Vec<PathPattern>,
+    pub(crate) skip: Option<SkipPredicate>,
+}
+
+impl fmt::Debug for PathFilter {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("PathFilter")
+            .field("include", &self.include)
+            .field("exclude", &self.exclude)
+            .field("skip", &self.skip.is_some())
+            .finish()
+    }
+}
+
+impl PathFilter {
+    /// Returns true if the request should not touch the session or user cache.
+    pub(crate) fn skips(&self, method: &Method, uri: &Uri) -> bool {
+        let path = uri.path();
+
+        (!self.include.is_empty() && !self.include.iter().any(|p| p.matches(path)))
+            || self.exclude.iter().any(|p| p.matches(path))
+            || self.skip.as_ref().is_some_and(|skip| skip(method, uri))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn glob(pattern: &'static str, path: &str) -> bool {
+        PathPattern::glob(pattern).matches(path)
+    }
+
+    #[test]
+    fn leading_double_star() {
+        assert!(glob("**/app.js", "/assets/js/app.js"));
+        assert!(glob("**/app.js", "app.js"));
+        assert!(!glob("**/app.js", "/assets/js/main.js"));
+    }
+
+    #[test]
+    fn middle_double_star() {
+        assert!(glob("/static/**/*.css", "/static/site.css"));
+        assert!(glob("/static/**/*.css", "/static/css/site.css"));
+        assert!(glob("/static/**/*.css", "/static/a/b/c/site.css"));
+        assert!(!glob("/static/**/*.css", "/static/css/site.js"));
+        assert!(!glob("/static/**/*.css", "/public/css/site.css"));
+    }
+
+    #[test]
+    fn trailing_double_star() {
+        assert!(glob("/admin/**", "/admin/"));
+        assert!(glob("/admin/**", "/admin/users"));
+        assert!(glob("/admin/**", "/admin/users/1/edit"));
+        assert!(!glob("/admin/**", "/administrator"));
+        assert!(!glob("/admin/**", "/api/admin/users"));
+    }
+
+    #[test]
+    fn single_star_stays_within_a_segment() {
+        assert!(glob("/users/*/edit", "/users/42/edit"));
+        assert!(glob("/files/*.txt", "/files/notes.txt"));
+        assert!(glob("/files/*.txt", "/files/.txt"));
+        assert!(!glob("/users/*/edit", "/users/42/posts/edit"));
+        assert!(!glob("/files/*.txt", "/files/a/notes.txt"));
+    }
+
+    #[test]
+    fn exact_match() {
+        assert!(glob("/health?", "/healthz"));
+        assert!(PathPattern::glob("/healthz").matches("/healthz"));
+        assert!(!PathPattern::glob("/healthz").matches("/healthz/live"));
+        assert!(!PathPattern::glob("/healthz").matches("/health"));
+    }
+}
@@ -0,0 +1,65 @@
+use crate::{DenyReason, RequestInfo};
+use anyhow::Error;
+use async_trait::async_trait;
+use axum_session::{DatabasePool, Session};
+use serde::{de::DeserializeOwned, Serialize};
+use std::{fmt, hash::Hash};
+
+/// Callbacks run on login, logout and user load events, for auditing and side effects.
+///
+/// Every callback does nothing by default. All callbacks are awaited before the call
+/// that triggered them returns, so slow hooks slow down the request. The synchronous
+/// login_user, login_user_with_method and logout_user do not run the hooks.
+///
+/// # Examples
+/// ```rust no_run ignore
+/// #[derive(Debug)]
+/// struct LogHooks;
+///
+/// #[async_trait]
+/// impl AuthHooks<i64, SessionPgPool> for LogHooks {
+///     async fn on_login(&self, id: &i64, request: &RequestInfo, _session: &Session<SessionPgPool>) {
+///         tracing::info!("user {} logged in via {}", id, request.uri);
+///     }
+/// }
+///
+/// let layer = AuthSessionLayer::<User, i64, SessionPgPool, PgPool>::new(Some(pool))
+///     .with_hooks(Arc::new(LogHooks));
+/// ```
+///
+#[async_trait]
+pub trait AuthHooks<Type, Sess>: fmt::Debug + Send + Sync
+where
+    Type: Eq + Default + Clone + Send + Sync + Hash + Serialize + DeserializeOwned + 'static,
+    Sess: DatabasePool + Clone + fmt::Debug + Sync + Send + 'static,
+{
+    /// Called after login_user_with_hooks set the user id in the session.
+    async fn on_login(&self, _id: &Type, _request: &RequestInfo, _session: &Session<Sess>) {}
+
+    /// Called after logout_user_with_hooks removed the user id from the session.
+    async fn on_logout(&self, _id: &Type, _request: &RequestInfo, _session: &Session<Sess>) {}
+
+    /// Called when the user of a request was loaded, either from the cache or load_user.
+    async fn on_user_loaded(&self, _id: &Type, _request: &RequestInfo, _session: &Session<Sess>) {}
+
+    /// Called when load_user failed with a transient error.
+    async fn on_load_failed(
+        &self,
+        _id: &Type,
+        _error: &Error,
+        _request: &RequestInfo,
+        _session: &Session<Sess>,
+    ) {
+    }
+
+    /// Called when `Auth::check_session`, `validate_session` or `AuthSession::authorize` denied access.
+    async fn on_access_denied(
+        &self,
+        _id: &Type,
+        _policy: &str,
+        _reason: DenyReason,
+        _request: &RequestInfo,
+        _session: &Session<Sess>,
+    ) {
+    }
+}
@@ -0,0 +1,546 @@
+use crate::{
+    authenticator::bearer_challenge, request::bearer_token, AuthMechanism, AuthRejection,
+    AuthRequest, Authenticated, Authenticator, Scopes,
+};
+use async_trait::async_trait;
+use http::HeaderMap;
+use jsonwebtoken::{
+    decode, decode_header,
+    jwk::{JwkSet, KeyAlgorithm},
+    Algorithm, DecodingKey, Validation,
+};
+use serde::de::DeserializeOwned;
+use serde_json::Value;
+use std::{borrow::Cow, collections::HashSet, fmt};
+
+/// The verified claims of a JWT the request authenticated with.
+///
+#[derive(Debug, Clone)]
+pub struct JwtClaims<Type> {
+    /// The user id read from the configured user claim.
+    pub id: Type,
+    /// The scopes read from the configured scope claim, if one is set.
+    pub scopes: Option<HashSet<String>>,
+    /// All claims of the token.
+    pub claims: Value,
+}
+
+/// Why a JWT sent with a request was not accepted.
+///
+#[derive(Debug)]
+enum JwtError {
+    /// No key matches the tokens kid and algorithm.
+    UnknownKey,
+    /// The signature or a registered claim is not valid.
+    Invalid(jsonwebtoken::errors::Error),
+    /// The user claim is missing or not a valid user id.
+    UserClaim,
+}
+
+impl fmt::Display for JwtError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            JwtError::UnknownKey => f.write_str("no key matches the token"),
+            JwtError::Invalid(err) => write!(f, "invalid token: {}", err),
+            JwtError::UserClaim => f.write_str("the user claim is missing or invalid"),
+        }
+    }
+}
+
+#[derive(Clone)]
+struct JwtKey {
+    kid: Option<String>,
+    algorithm: Algorithm,
+    key: DecodingKey,
+}
+
+/// Authenticator verifying `Authorization: Bearer` JWTs from a local key set.
+///
+/// Requests with a valid token are authenticated as the user in the user claim, which
+/// is loaded through load_user and the cache like a session user. Requests with an
+/// invalid token are rejected with 401 Unauthorized. Tokens must have an exp claim.
+/// The JwtClaims are kept in `AuthSession::auth_extensions`.
+///
+/// # Examples
+/// ```rust no_run ignore
+/// let jwt = JwtAuth::default()
+///     .with_rsa_pem(Some("gateway-2024"), include_bytes!("gateway.pub.pem"))?
+///     .with_issuer(["https://gateway.example.com"])
+///     .with_audience(["orders-api"])
+///     .with_scope_claim("scope");
+///
+/// let layer = AuthSessionLayer::<User, i64, SessionPgPool, PgPool>::new(Some(pool))
+///     .with_jwt(jwt);
+/// ```
+///
+#[derive(Clone)]
+pub struct JwtAuth {
+    keys: Vec<JwtKey>,
+    pub(crate) audience: Vec<String>,
+    pub(crate) issuer: Vec<String>,
+    pub(crate) leeway: u64,
+    pub(crate) user_claim: Cow<'static, str>,
+    pub(crate) scope_claim: Option<Cow<'static, str>>,
+}
+
+impl fmt::Debug for JwtAuth {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("JwtAuth")
+            .field("keys", &self.keys.len())
+            .field("audience", &self.audience)
+            .field("issuer", &self.issuer)
+            .field("leeway", &self.leeway)
+            .field("user_claim", &self.user_claim)
+            .field("scope_claim", &self.scope_claim)
+            .finish()
+    }
+}
+
+impl Default for JwtAuth {
+    fn default() -> Self {
+        Self {
+            keys: Vec::new(),
+            audience: Vec::new(),
+            issuer: Vec::new(),
+            leeway: 60,
+            user_claim: "sub".into(),
+            scope_claim: None,
+        }
+    }
+}
+
+impl JwtAuth {
+    /// Adds a verification key. Tokens with a kid header only use keys with the same kid.
+    ///
+    /// # Examples
+    /// ```rust no_run ignore
+    /// let jwt = JwtAuth::default().with_key(None, Algorithm::ES256, DecodingKey::from_ec_pem(pem)?);
+    /// ```
+    ///
+    #[must_use]
+    pub fn with_key(mut self, kid: Option<&str>, algorithm: Algorithm, key: DecodingKey) -> Self {
+        self.keys.push(JwtKey {
+            kid: kid.map(str::to_owned),
+            algorithm,
+            key,
+        });
+        self
+    }
+
+    /// Adds a HS256 shared secret.
+    ///
+    /// # Examples
+    /// ```rust
+    /// use axum_session_auth::JwtAuth;
+    ///
+    /// let jwt = JwtAuth::default().with_hs256_secret(None, b"a long random secret");
+    /// ```
+    ///
+    #[must_use]
+    pub fn with_hs256_secret(self, kid: Option<&str>, secret: &[u8]) -> Self {
+        self.with_key(kid, Algorithm::HS256, DecodingKey::from_secret(secret))
+    }
+
+    /// Adds a RS256 public key in PEM format.
+    pub fn with_rsa_pem(
+        self,
+        kid: Option<&str>,
+        pem: &[u8],
+    ) -> Result<Self, jsonwebtoken::errors::Error> {
+        Ok(self.with_key(kid, Algorithm::RS256, DecodingKey::from_rsa_pem(pem)?))
+    }
+
+    /// Adds an EdDSA public key in PEM format.
+    pub fn with_ed_pem(
+        self,
+        kid: Option<&str>,
+        pem: &[u8],
+    ) -> Result<Self, jsonwebtoken::errors::Error> {
+        Ok(self.with_key(kid, Algorithm::EdDSA, DecodingKey::from_ed_pem(pem)?))
+    }
+
+    /// Adds the HS256, RS256 and EdDSA keys of a JWK set, like a copy of an issuers jwks.json.
+    ///
+    /// Keys without an alg are added for the algorithm their key type implies.
+    pub fn with_jwk_set(mut self, set: &JwkSet) -> Result<Self, jsonwebtoken::errors::Error> {
+        for jwk in &set.keys {
+            let algorithm = match jwk.common.key_algorithm {
+                Some(KeyAlgorithm::HS256) => Algorithm::HS256,
+                Some(KeyAlgorithm::RS256) => Algorithm::RS256,
+                Some(KeyAlgorithm::EdDSA) => Algorithm::EdDSA,
+                Some(_) => continue,
+                None => match &jwk.algorithm {
+                    jsonwebtoken::jwk::AlgorithmParameters::RSA(_) => Algorithm::RS256,
+                    jsonwebtoken::jwk::AlgorithmParameters::OctetKeyPair(_) => Algorithm::EdDSA,
+                    jsonwebtoken::jwk::AlgorithmParameters::OctetKey(_) => Algorithm::HS256,
+                    _ => continue,
+                },
+            };
+
+            self.keys.push(JwtKey {
+                kid: jwk.common.key_id.clone(),
+                algorithm,
+                key: DecodingKey::from_jwk(jwk)?,
+            });
+        }
+
+        Ok(self)
+    }
+
+    /// Set's the accepted aud values. The aud claim is not checked if empty. Defaults to empty.
+    ///
+    /// # Examples
+    /// ```rust
+    /// use axum_session_auth::JwtAuth;
+    ///
+    /// let jwt = JwtAuth::default().with_audience(["orders-api"]);
+    /// ```
+    ///
+    #[must_use]
+    pub fn with_audience(mut self, audience: impl IntoIterator<Item = impl Into<String>>) -> Self {
+        self.audience = audience.into_iter().map(Into::into).collect();
+        self
+    }
+
+    /// Set's the accepted iss values. The iss claim is not checked if empty. Defaults to empty.
+    ///
+    /// # Examples
+    /// ```rust
+    /// use axum_session_auth::JwtAuth;
+    ///
+    /// let jwt = JwtAuth::default().with_issuer(["https://gateway.example.com"]);
+    /// ```
+    ///
+    #[must_use]
+    pub fn with_issuer(mut self, issuer: impl IntoIterator<Item = impl Into<String>>) -> Self {
+        self.issuer = issuer.into_iter().map(Into::into).collect();
+        self
+    }
+
+    /// Set's the seconds of clock skew allowed for exp and nbf. Defaults to 60.
+    ///
+    /// # Examples
+    /// ```rust
+    /// use axum_session_auth::JwtAuth;
+    ///
+    /// let jwt = JwtAuth::default().with_leeway(10);
+    /// ```
+    ///
+    #[must_use]
+    pub fn with_leeway(mut self, leeway: u64) -> Self {
+        self.leeway = leeway;
+        self
+    }
+
+    /// Set's the claim holding the user id. Defaults to `sub`.
+    ///
+    /// String claims are also parsed as JSON, so `"42"` works for numeric ids.
+    ///
+    /// # Examples
+    /// ```rust
+    /// use axum_session_auth::JwtAuth;
+    ///
+    /// let jwt = JwtAuth::default().with_user_claim("uid");
+    /// ```
+    ///
+    #[must_use]
+    pub fn with_user_claim(mut self, claim: impl Into<Cow<'static, str>>) -> Self {
+        self.user_claim = claim.into();
+        self
+    }
+
+    /// Set's the claim holding the tokens scopes, as a space separated string or an array.
+    ///
+    /// When set, the scopes replace the users HasPermission in `AuthSession::authorize`,
+    /// as `Scopes::Replace`. Defaults to None.
+    ///
+    /// # Examples
+    /// ```rust
+    /// use axum_session_auth::JwtAuth;
+    ///
+    /// let jwt = JwtAuth::default().with_scope_claim("scope");
+    /// ```
+    ///
+    #[must_use]
+    pub fn with_scope_claim(mut self, claim: impl Into<Cow<'static, str>>) -> Self {
+        self.scope_claim = Some(claim.into());
+        self
+    }
+
+    /// Verifies the bearer token sent with the request. Ok(None) if no JWT was sent.
+    fn resolve<Type: DeserializeOwned>(
+        &self,
+        headers: &HeaderMap,
+    ) -> Result<Option<JwtClaims<Type>>, JwtError> {
+        let Some(token) = bearer_token(headers).filter(|token| token.matches('.').count() == 2)
+        else {
+            return Ok(None);
+        };
+
+        let header = decode_header(token).map_err(JwtError::Invalid)?;
+        let mut last_error = JwtError::UnknownKey;
+
+        let keys = self.keys.iter().filter(|key| {
+            key.algorithm == header.alg && (header.kid.is_none() || key.kid == header.kid)
+        });
+
+        for key in keys {
+            let mut validation = Validation::new(key.algorithm);
+            validation.leeway = self.leeway;
+            validation.validate_nbf = true;
+            validation.validate_aud = !self.audience.is_empty();
+
+            if !self.audience.is_empty() {
+                validation.set_audience(&self.audience);
+            }
+
+            if !self.issuer.is_empty() {
+                validation.set_issuer(&self.issuer);
+            }
+
+            match decode::<Value>(token, &key.key, &validation) {
+                Ok(data) => return self.claims(data.claims).map(Some),
+                Err(err) => last_error = JwtError::Invalid(err),
+            }
+        }
+
+        Err(last_error)
+    }
+
+    fn claims<Type: DeserializeOwned>(&self, claims: Value) -> Result<JwtClaims<Type>, JwtError> {
+        let id = match claims.get(self.user_claim.as_ref()) {
+            Some(Value::String(id)) => serde_json::from_value(Value::String(id.clone()))
+                .or_else(|_| serde_json::from_str(id)),
+            Some(id) => serde_json::from_value(id.clone()),
+            None => return Err(JwtError::UserClaim),
+        }
+        .map_err(|_| JwtError::UserClaim)?;
+
+        let scopes = self
+            .scope_claim
+            .as_ref()
+            .map(|claim| match claims.get(claim.as_ref()) {
+                Some(Value::String(scopes)) => {
+                    scopes.split_whitespace().map(str::to_owned).collect()
+                }
+                Some(Value::Array(scopes)) => scopes
+                    .iter()
+                    .filter_map(|scope| scope.as_str().map(str::to_owned))
+                    .collect(),
+                _ => HashSet::new(),
+            });
+
+        Ok(JwtClaims { id, scopes, claims })
+    }
+}
+
+#[async_trait]
+impl<Type, Pool> Authenticator<Type, Pool> for JwtAuth
+where
+    Type: DeserializeOwned + Clone + Send + Sync + 'static,
+    Pool: Send + Sync,
+{
+    async fn authenticate(
+        &self,
+        request: &AuthRequest<'_, Type, Pool>,
+    ) -> Result<Option<Authenticated<Type>>, AuthRejection> {
+        let claims = match self.resolve::<Type>(request.headers) {
+            Ok(Some(claims)) => claims,
+            Ok(None) => return Ok(None),
+            Err(err) => {
+                tracing::debug!("rejected jwt: {}", err);
+                return Err(AuthRejection::Unauthorized(Some(bearer_challenge())));
+            }
+        };
+
+        let mut authenticated = Authenticated::new(claims.id.clone(), AuthMechanism::Jwt);
+
+        if let Some(scopes) = &claims.scopes {
+            authenticated.scopes = Scopes::Replace(scopes.clone());
+        }
+
+        authenticated.extensions.insert(claims);
+        Ok(Some(authenticated))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        testing::{Client, TestLayer, User, ADMIN},
+        Auth, DenyReason, Rights,
+    };
+    use http::{HeaderValue, Method, Request, StatusCode};
+    use jsonwebtoken::{encode, EncodingKey, Header};
+    use serde_json::json;
+
+    const SECRET: &[u8] = b"a long random secret";
+
+    fn now() -> i64 {
+        chrono::Utc::now().timestamp()
+    }
+
+    fn token(header: Header, claims: Value) -> HeaderMap {
+        let token = encode(&header, &claims, &EncodingKey::from_secret(SECRET)).unwrap();
+        let mut headers = HeaderMap::new();
+        headers.insert(
+            http::header::AUTHORIZATION,
+            HeaderValue::from_str(&format!("Bearer {token}")).unwrap(),
+        );
+        headers
+    }
+
+    fn jwt() -> JwtAuth {
+        JwtAuth::default()
+            .with_hs256_secret(Some("current"), SECRET)
+            .with_audience(["orders-api"])
+            .with_issuer(["https://gateway.example.com"])
+            .with_leeway(10)
+    }
+
+    fn payload(extra: Value) -> Value {
+        let mut payload = json!({
+            "sub": "42",
+            "aud": "orders-api",
+            "iss": "https://gateway.example.com",
+            "exp": now() + 60,
+        });
+        payload
+            .as_object_mut()
+            .unwrap()
+            .extend(extra.as_object().unwrap().clone());
+        payload
+    }
+
+    fn resolve(headers: &HeaderMap) -> Result<Option<JwtClaims<i64>>, JwtError> {
+        jwt().resolve::<i64>(headers)
+    }
+
+    #[test]
+    fn valid_tokens_resolve_to_their_user() {
+        let claims = resolve(&token(Header::default(), payload(json!({})))).unwrap();
+        assert_eq!(claims.map(|claims| claims.id), Some(42));
+
+        let header = Header {
+            kid: Some("current".into()),
+            ..Header::default()
+        };
+        assert!(resolve(&token(header, payload(json!({})))).is_ok());
+
+        assert!(resolve(&HeaderMap::new()).unwrap().is_none());
+    }
+
+    #[test]
+    fn tokens_need_a_key_with_their_alg_and_kid() {
+        let header = Header::new(Algorithm::HS384);
+        assert!(matches!(
+            resolve(&token(header, payload(json!({})))),
+            Err(JwtError::UnknownKey)
+        ));
+
+        let header = Header {
+            kid: Some("retired".into()),
+            ..Header::default()
+        };
+        assert!(matches!(
+            resolve(&token(header, payload(json!({})))),
+            Err(JwtError::UnknownKey)
+        ));
+    }
+
+    #[test]
+    fn registered_claims_are_checked() {
+        let rejected = [
+            json!({ "aud": "billing-api" }),
+            json!({ "iss": "https://evil.example.com" }),
+            json!({ "exp": now() - 60 }),
+            json!({ "nbf": now() + 60 }),
+        ];
+
+        for extra in rejected {
+            assert!(
+                matches!(
+                    resolve(&token(Header::default(), payload(extra.clone()))),
+                    Err(JwtError::Invalid(_))
+                ),
+                "{extra} was accepted"
+            );
+        }
+
+        // Within the leeway.
+        let skewed = json!({ "exp": now() - 5, "nbf": now() + 5 });
+        assert!(resolve(&token(Header::default(), payload(skewed))).is_ok());
+
+        let mut payload = payload(json!({}));
+        payload.as_object_mut().unwrap().remove("exp");
+        assert!(matches!(
+            resolve(&token(Header::default(), payload)),
+            Err(JwtError::Invalid(_))
+        ));
+    }
+
+    #[test]
+    fn the_user_claim_must_be_a_user_id() {
+        assert!(matches!(
+            resolve(&token(
+                Header::default(),
+                payload(json!({ "sub": "alice" }))
+            )),
+            Err(JwtError::UserClaim)
+        ));
+        assert!(matches!(
+            jwt()
+                .with_user_claim("uid")
+                .resolve::<i64>(&token(Header::default(), payload(json!({ "uid": 7 })))),
+            Ok(Some(JwtClaims { id: 7, .. }))
+        ));
+    }
+
+    #[tokio::test]
+    async fn scopes_replace_the_users_permissions() {
+        let client =
+            Client::new(TestLayer::new(None).with_jwt(jwt().with_scope_claim("scope"))).await;
+        let headers = token(
+            Header::default(),
+            payload(json!({ "sub": ADMIN.to_string(), "scope": "read write" })),
+        );
+        let mut request = Request::get("/");
+        request.headers_mut().unwrap().extend(headers);
+
+        let (_, result) = client
+            .send(request, |auth| async move {
+                let guard = |permission| {
+                    let mut guard = Auth::<User, i64, ()>::build([Method::GET], true);
+                    guard.requires(Rights::permission(permission));
+                    guard
+                };
+
+                (
+                    auth.mechanism.clone(),
+                    auth.authorize(&guard("write"), &Method::GET).await,
+                    auth.authorize(&guard("admin"), &Method::GET).await,
+                )
+            })
+            .await;
+
+        assert_eq!(
+            result,
+            Some((Some(AuthMechanism::Jwt), Ok(()), Err(DenyReason::Rights)))
+        );
+
+        let mut request = Request::get("/");
+        request.headers_mut().unwrap().extend(token(
+            Header::default(),
+            payload(json!({ "exp": now() - 60 })),
+        ));
+        let (response, result) = client.send(request, |_| async {}).await;
+
+        assert_eq!(response.status(), StatusCode::UNAUTHORIZED);
+        assert_eq!(
+            response.headers().get(http::header::WWW_AUTHENTICATE),
+            Some(&bearer_challenge())
+        );
+        assert_eq!(result, None);
+    }
+}
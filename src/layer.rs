@@ -1,8 +1,13 @@
-use crate::{AuthCache, AuthConfig, AuthSessionService, Authentication};
+use crate::{
+    filter::PathFilter, AuditSink, AuthCache, AuthConfig, AuthHooks, AuthSessionService,
+    Authentication, Authenticator, InvalidationBus, MissingSession, PathPattern,
+    SessionAuthenticator,
+};
 use axum_session::DatabasePool;
 use chrono::{Duration, Utc};
+use http::{Method, Uri};
 use serde::{de::DeserializeOwned, Serialize};
-use std::{fmt, hash::Hash, marker::PhantomData};
+use std::{fmt, hash::Hash, marker::PhantomData, sync::Arc};
 use tower_layer::Layer;
 
 /// Layer used to generate an AuthSessionService.
@@ -17,6 +22,17 @@ where
     pub(crate) pool: Option<Pool>,
     pub(crate) config: AuthConfig<Type>,
     pub(crate) cache: AuthCache<User, Type, Pool>,
+    pub(crate) filter: PathFilter,
+    pub(crate) missing_session: MissingSession,
+    pub(crate) hooks: Option<Arc<dyn AuthHooks<Type, Sess>>>,
+    pub(crate) audit: Option<Arc<dyn AuditSink>>,
+    #[cfg(feature = "api-key")]
+    pub(crate) api_keys: Option<Arc<crate::ApiKeys<Type, Pool>>>,
+    #[cfg(feature = "jwt")]
+    pub(crate) jwt: Option<Arc<crate::JwtAuth>>,
+    #[cfg(feature = "basic-auth")]
+    pub(crate) basic_auth: Option<Arc<crate::BasicAuth<Type, Pool>>>,
+    pub(crate) authenticators: Option<Vec<Arc<dyn Authenticator<Type, Pool>>>>,
     pub phantom_user: PhantomData<User>,
     pub phantom_session: PhantomData<Sess>,
     pub phantom_type: PhantomData<Type>,
@@ -45,6 +61,17 @@ where
             cache: AuthCache::<User, Type, Pool>::new(
                 Utc::now() + Duration::try_hours(1).unwrap_or_default(),
             ),
+            filter: PathFilter::default(),
+            missing_session: MissingSession::default(),
+            hooks: None,
+            audit: None,
+            #[cfg(feature = "api-key")]
+            api_keys: None,
+            #[cfg(feature = "jwt")]
+            jwt: None,
+            #[cfg(feature = "basic-auth")]
+            basic_auth: None,
+            authenticators: None,
             phantom_user: PhantomData,
             phantom_session: PhantomData,
             phantom_type: PhantomData,
@@ -53,9 +80,284 @@ where
 
     #[must_use]
     pub fn with_config(mut self, config: AuthConfig<Type>) -> Self {
+        self.cache.permissions.max_age = config.permission_max_age;
         self.config = config;
         self
     }
+
+    /// Only handle requests whose path matches one of these patterns.
+    /// All other requests are passed to the inner service without an AuthSession.
+    ///
+    /// # Examples
+    /// ```rust no_run ignore
+    ///    let layer = AuthSessionLayer::<User, i64, Sess, Pool>::new(None)
+    ///        .with_include_paths(["/api", "/admin/**"]);
+    /// ```
+    ///
+    #[must_use]
+    pub fn with_include_paths(
+        mut self,
+        patterns: impl IntoIterator<Item = impl Into<PathPattern>>,
+    ) -> Self {
+        self.filter
+            .include
+            .extend(patterns.into_iter().map(Into::into));
+        self
+    }
+
+    /// Skip requests whose path matches one of these patterns.
+    /// They are passed to the inner service without touching the session or user cache.
+    ///
+    /// # Examples
+    /// ```rust no_run ignore
+    ///    let layer = AuthSessionLayer::<User, i64, Sess, Pool>::new(None)
+    ///        .with_exclude_paths(["/healthz", "/metrics", "/assets/*.js"]);
+    /// ```
+    ///
+    #[must_use]
+    pub fn with_exclude_paths(
+        mut self,
+        patterns: impl IntoIterator<Item = impl Into<PathPattern>>,
+    ) -> Self {
+        self.filter
+            .exclude
+            .extend(patterns.into_iter().map(Into::into));
+        self
+    }
+
+    /// Skip requests the predicate returns true for, in addition to the path patterns.
+    ///
+    /// # Examples
+    /// ```rust no_run ignore
+    ///    let layer = AuthSessionLayer::<User, i64, Sess, Pool>::new(None)
+    ///        .with_skip(|method, _uri| method == Method::OPTIONS);
+    /// ```
+    ///
+    #[must_use]
+    pub fn with_skip<F>(mut self, predicate: F) -> Self
+    where
+        F: Fn(&Method, &Uri) -> bool + Send + Sync + 'static,
+    {
+        self.filter.skip = Some(Arc::new(predicate));
+        self
+    }
+
+    /// Sets what happens to requests when the axum_session `SessionLayer` did not run first.
+    ///
+    /// Defaults to [`MissingSession::Reject`] which responds with an empty 500.
+    ///
+    /// # Examples
+    /// ```rust no_run ignore
+    ///    let layer = AuthSessionLayer::<User, i64, Sess, Pool>::new(None)
+    ///        .with_missing_session(MissingSession::handler(|err| {
+    ///            (StatusCode::INTERNAL_SERVER_ERROR, err.to_string()).into_response()
+    ///        }));
+    /// ```
+    ///
+    #[must_use]
+    pub fn with_missing_session(mut self, missing_session: MissingSession) -> Self {
+        self.missing_session = missing_session;
+        self
+    }
+
+    /// Sets the hooks called on login, logout, user load and access denied events.
+    ///
+    /// # Examples
+    /// ```rust no_run ignore
+    ///    let layer = AuthSessionLayer::<User, i64, Sess, Pool>::new(None)
+    ///        .with_hooks(Arc::new(AuditHooks::default()));
+    /// ```
+    ///
+    #[must_use]
+    pub fn with_hooks(mut self, hooks: Arc<dyn AuthHooks<Type, Sess>>) -> Self {
+        self.hooks = Some(hooks);
+        self
+    }
+
+    /// Sets the sink AuditEvents are recorded to for logins, logouts, denied
+    /// `AuthSession::authorize` calls and cache invalidations.
+    ///
+    /// # Examples
+    /// ```rust no_run ignore
+    ///    let layer = AuthSessionLayer::<User, i64, Sess, Pool>::new(None)
+    ///        .with_audit_sink(Arc::new(JsonLinesSink::open("audit.log")?));
+    /// ```
+    ///
+    #[must_use]
+    pub fn with_audit_sink(mut self, sink: Arc<dyn AuditSink>) -> Self {
+        self.audit = Some(sink);
+        self
+    }
+
+    /// Sets the API keys requests can authenticate with instead of the session.
+    ///
+    /// # Examples
+    /// ```rust no_run ignore
+    ///    let layer = AuthSessionLayer::<User, i64, Sess, Pool>::new(None)
+    ///        .with_api_keys(ApiKeys::new(Arc::new(Keys)));
+    /// ```
+    ///
+    #[cfg(feature = "api-key")]
+    #[must_use]
+    pub fn with_api_keys(mut self, api_keys: crate::ApiKeys<Type, Pool>) -> Self {
+        self.api_keys = Some(Arc::new(api_keys));
+        self
+    }
+
+    /// Sets the JWT verification requests can authenticate with instead of the session.
+    ///
+    /// Bearer tokens shaped like a JWT are verified here first, other bearer tokens are
+    /// left for the API keys.
+    ///
+    /// # Examples
+    /// ```rust no_run ignore
+    ///    let layer = AuthSessionLayer::<User, i64, Sess, Pool>::new(None)
+    ///        .with_jwt(JwtAuth::default().with_hs256_secret(None, secret));
+    /// ```
+    ///
+    #[cfg(feature = "jwt")]
+    #[must_use]
+    pub fn with_jwt(mut self, jwt: crate::JwtAuth) -> Self {
+        self.jwt = Some(Arc::new(jwt));
+        self
+    }
+
+    /// Sets the HTTP Basic credentials requests can authenticate with.
+    ///
+    /// # Examples
+    /// ```rust no_run ignore
+    ///    let layer = AuthSessionLayer::<User, i64, Sess, Pool>::new(None)
+    ///        .with_basic_auth(BasicAuth::new(Arc::new(Users)).with_mode(BasicAuthMode::Session));
+    /// ```
+    ///
+    #[cfg(feature = "basic-auth")]
+    #[must_use]
+    pub fn with_basic_auth(mut self, basic_auth: crate::BasicAuth<Type, Pool>) -> Self {
+        self.basic_auth = Some(Arc::new(basic_auth));
+        self
+    }
+
+    /// Sets the ordered chain of authenticators asked for each request's user.
+    ///
+    /// The first to recognise the request authenticates it. Replaces the default chain of
+    /// with_jwt, with_api_keys, with_basic_auth and then the session, so include a
+    /// SessionAuthenticator to keep session logins working.
+    ///
+    /// # Examples
+    /// ```rust no_run ignore
+    ///    let layer = AuthSessionLayer::<User, i64, Sess, Pool>::new(None)
+    ///        .with_authenticators([
+    ///            Arc::new(MutualTls) as Arc<dyn Authenticator<i64, Pool>>,
+    ///            Arc::new(SessionAuthenticator),
+    ///        ]);
+    /// ```
+    ///
+    #[must_use]
+    pub fn with_authenticators(
+        mut self,
+        authenticators: impl IntoIterator<Item = Arc<dyn Authenticator<Type, Pool>>>,
+    ) -> Self {
+        self.authenticators = Some(authenticators.into_iter().collect());
+        self
+    }
+
+    /// Returns the authenticators the services ask in order.
+    fn authenticator_chain(&self) -> Arc<[Arc<dyn Authenticator<Type, Pool>>]> {
+        if let Some(authenticators) = &self.authenticators {
+            return authenticators.iter().cloned().collect();
+        }
+
+        let chain: Vec<Option<Arc<dyn Authenticator<Type, Pool>>>> = vec![
+            #[cfg(feature = "jwt")]
+            self.jwt.clone().map(|jwt| jwt as _),
+            #[cfg(feature = "api-key")]
+            self.api_keys.clone().map(|api_keys| api_keys as _),
+            #[cfg(feature = "basic-auth")]
+            self.basic_auth.clone().map(|basic_auth| basic_auth as _),
+            Some(Arc::new(SessionAuthenticator)),
+        ];
+
+        chain.into_iter().flatten().collect()
+    }
+
+    /// Returns the user cache shared by all services this layer creates.
+    ///
+    /// Can be kept to read the cache's stats and entries.
+    ///
+    /// # Examples
+    /// ```rust no_run ignore
+    ///    let layer = AuthSessionLayer::<User, i64, Sess, Pool>::new(None);
+    ///    let cache = layer.cache().clone();
+    /// ```
+    ///
+    pub fn cache(&self) -> &AuthCache<User, Type, Pool> {
+        &self.cache
+    }
+}
+
+impl<User, Type, Sess, Pool> AuthSessionLayer<User, Type, Sess, Pool>
+where
+    User: Authentication<User, Type, Pool> + Clone + Send + Sync + 'static,
+    Pool: Clone + Send + Sync + fmt::Debug + 'static,
+    Type: Eq
+        + Default
+        + Clone
+        + Send
+        + Sync
+        + Hash
+        + Serialize
+        + DeserializeOwned
+        + fmt::Display
+        + 'static,
+    Sess: DatabasePool + Clone + Sync + Send + 'static,
+{
+    /// Sets the bus used to share user cache clears between multiple app instances.
+    ///
+    /// The cache subscribes to the bus in a spawned task so this must be called within a tokio runtime.
+    ///
+    /// # Examples
+    /// ```rust no_run ignore
+    ///    let layer = AuthSessionLayer::<User, i64, Sess, Pool>::new(None)
+    ///        .with_invalidation_bus(Arc::new(BroadcastBus::new(64)));
+    /// ```
+    ///
+    #[must_use]
+    pub fn with_invalidation_bus(mut self, bus: Arc<dyn InvalidationBus<Type>>) -> Self {
+        self.cache.set_bus(bus);
+        self
+    }
+
+    /// Loads the given users into the cache so the first requests after startup do not call load_user.
+    ///
+    /// Users are loaded one at a time. Returns how many users were loaded.
+    /// Does nothing if caching is disabled in the config.
+    ///
+    /// # Examples
+    /// ```rust no_run ignore
+    ///    let layer = AuthSessionLayer::<User, i64, Sess, Pool>::new(Some(pool)).with_config(config);
+    ///    layer.warm_cache(recent_user_ids).await;
+    /// ```
+    ///
+    pub async fn warm_cache(&self, ids: impl IntoIterator<Item = Type>) -> usize {
+        if !self.config.cache {
+            return 0;
+        }
+
+        let mut loaded = 0;
+
+        for id in ids {
+            if let Ok(Some(_)) = self
+                .cache
+                .load_user(&id, self.pool.as_ref(), &self.config)
+                .await
+            {
+                loaded += 1;
+            }
+        }
+
+        tracing::info!("warmed user cache with {} users.", loaded);
+        loaded
+    }
 }
 
 impl<S, User, Type, Sess, Pool> Layer<S> for AuthSessionLayer<User, Type, Sess, Pool>
@@ -72,6 +374,11 @@ where
             pool: self.pool.clone(),
             config: self.config.clone(),
             cache: self.cache.clone(),
+            filter: Arc::new(self.filter.clone()),
+            missing_session: self.missing_session.clone(),
+            hooks: self.hooks.clone(),
+            audit: self.audit.clone(),
+            authenticators: self.authenticator_chain(),
             inner,
             phantom_session: PhantomData,
         }
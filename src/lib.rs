@@ -2,20 +2,84 @@
 #![forbid(unsafe_code)]
 ///This Library Requires that DatabaseSessions is used as an active layer.
 ///
+#[cfg(feature = "api-key")]
+mod api_key;
+mod audit;
 mod auth;
+mod authenticator;
+#[cfg(feature = "basic-auth")]
+mod basic;
+mod bus;
 mod cache;
 mod config;
+#[cfg(feature = "credentials")]
+mod credentials;
+mod filter;
+mod hooks;
+#[cfg(feature = "jwt")]
+mod jwt;
 mod layer;
+mod limiter;
+mod missing_session;
+#[cfg(feature = "oidc")]
+mod oidc;
+mod permissions;
+mod request;
 mod service;
 mod session;
+mod snapshot;
+mod telemetry;
+#[cfg(test)]
+mod testing;
+#[cfg(feature = "one-time-token")]
+mod token;
+#[cfg(feature = "totp")]
+mod totp;
 mod user;
 
-pub use auth::{Auth, HasPermission, Rights};
-pub use cache::AuthCache;
-pub use config::AuthConfig;
+#[cfg(feature = "api-key")]
+pub use api_key::{generate_api_key, hash_api_key, ApiKey, ApiKeyStore, ApiKeys};
+pub use audit::{AuditEvent, AuditKind, AuditSink, JsonLinesSink, MemorySink};
+pub use auth::{Auth, DenyReason, HasPermission, Rights};
+pub use authenticator::{
+    AuthMechanism, AuthRejection, AuthRequest, Authenticated, Authenticator, Scopes,
+    SessionAuthenticator,
+};
+#[cfg(feature = "basic-auth")]
+pub use basic::{BasicAuth, BasicAuthMode, CredentialVerifier};
+pub use bus::{BroadcastBus, Invalidation, InvalidationBus};
+pub use cache::{AuthCache, CacheStats};
+pub use config::{AuthConfig, Degraded};
+#[cfg(feature = "credentials")]
+pub use credentials::{CredentialStore, Credentials, LoginError, PasswordHashing};
+pub use filter::PathPattern;
+pub use hooks::AuthHooks;
+#[cfg(feature = "jwt")]
+pub use jwt::{JwtAuth, JwtClaims};
 pub use layer::AuthSessionLayer;
+pub use limiter::{
+    AttemptKey, AttemptState, AttemptStore, LoginLimiter, MemoryAttemptStore, ReservedAttempt,
+};
+pub use missing_session::{MissingSession, MissingSessionHandler, MissingSessionLayer};
+#[cfg(feature = "oidc")]
+pub use oidc::{
+    OidcCallback, OidcClaims, OidcClient, OidcDiscovery, OidcError, OidcHttp, OidcSubjectMapper,
+};
+pub use permissions::PermissionCache;
+pub use request::RequestInfo;
 pub use service::AuthSessionService;
-pub use session::{AuthSession, Authentication};
+pub use session::{AuthSession, Authentication, LoadFailure, LoadTimeout, LoginInfo, UserNotFound};
+
+#[cfg(feature = "one-time-token")]
+pub use token::{
+    generate_one_time_token, hash_one_time_token, MemoryTokenStore, OneTimeToken, OneTimeTokens,
+    TokenError, TokenStore,
+};
+#[cfg(feature = "totp")]
+pub use totp::{
+    generate_recovery_codes, hash_recovery_code, SecondFactor, SecondFactorError, Totp,
+    TotpAlgorithm, TotpStore,
+};
 
 #[cfg(feature = "advanced")]
 pub use session::AuthStatus;
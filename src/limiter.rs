@@ -0,0 +1,555 @@
+use anyhow::Error;
+use async_trait::async_trait;
+use chrono::{DateTime, Duration, Utc};
+use dashmap::DashMap;
+use serde::Serialize;
+use std::{
+    fmt,
+    net::IpAddr,
+    sync::{
+        atomic::{AtomicI64, Ordering},
+        Arc, Mutex,
+    },
+};
+
+/// What failed login attempts are counted against.
+///
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub enum AttemptKey {
+    /// A user id, as JSON so the store does not need to know the id type.
+    User(String),
+    /// A username as entered, lowercased.
+    Username(String),
+    /// The client ip of the request.
+    Ip(IpAddr),
+}
+
+impl AttemptKey {
+    /// Creates a User key from a user id.
+    pub fn user<Type: Serialize>(id: &Type) -> Self {
+        AttemptKey::User(serde_json::to_string(id).unwrap_or_default())
+    }
+
+    /// Creates a Username key, lowercasing the username.
+    pub fn username(username: &str) -> Self {
+        AttemptKey::Username(username.to_lowercase())
+    }
+}
+
+/// Failed attempts recorded for an AttemptKey.
+///
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct AttemptState {
+    pub failures: u32,
+    pub last_failure: DateTime<Utc>,
+    /// No attempts are allowed before this time.
+    pub blocked_until: Option<DateTime<Utc>>,
+}
+
+/// Storage for failed login attempts, so limits can be shared between servers.
+///
+#[async_trait]
+pub trait AttemptStore: fmt::Debug + Send + Sync {
+    async fn get(&self, key: &AttemptKey) -> Result<Option<AttemptState>, Error>;
+    async fn set(&self, key: &AttemptKey, state: AttemptState) -> Result<(), Error>;
+    async fn remove(&self, key: &AttemptKey) -> Result<(), Error>;
+
+    /// Replaces the keys state with the one update returns for the current state.
+    ///
+    /// The default uses get and set, so two failures at the same time can be counted
+    /// once. Stores that can read and write in one step should override it.
+    async fn update(
+        &self,
+        key: &AttemptKey,
+        update: &(dyn for<'a> Fn(Option<&'a AttemptState>) -> AttemptState + Send + Sync),
+    ) -> Result<(), Error> {
+        let state = update(self.get(key).await?.as_ref());
+        self.set(key, state).await
+    }
+
+    /// Removes states whose last failure is before last_failure_before and that are not
+    /// blocked anymore. Does nothing by default, for stores that expire states themselves.
+    async fn sweep(&self, _last_failure_before: DateTime<Utc>) -> Result<(), Error> {
+        Ok(())
+    }
+}
+
+/// AttemptStore keeping attempts in memory, only shared within one process.
+///
+/// Forgotten attempts are swept at most once a minute.
+///
+#[derive(Debug, Clone, Default)]
+pub struct MemoryAttemptStore {
+    inner: Arc<DashMap<AttemptKey, AttemptState>>,
+    /// Unix timestamp of the last sweep.
+    last_sweep: Arc<AtomicI64>,
+}
+
+#[async_trait]
+impl AttemptStore for MemoryAttemptStore {
+    async fn get(&self, key: &AttemptKey) -> Result<Option<AttemptState>, Error> {
+        Ok(self.inner.get(key).map(|state| state.clone()))
+    }
+
+    async fn set(&self, key: &AttemptKey, state: AttemptState) -> Result<(), Error> {
+        self.inner.insert(key.clone(), state);
+        Ok(())
+    }
+
+    async fn remove(&self, key: &AttemptKey) -> Result<(), Error> {
+        self.inner.remove(key);
+        Ok(())
+    }
+
+    async fn update(
+        &self,
+        key: &AttemptKey,
+        update: &(dyn for<'a> Fn(Option<&'a AttemptState>) -> AttemptState + Send + Sync),
+    ) -> Result<(), Error> {
+        // The entry stays locked between reading and writing the state.
+        self.inner
+            .entry(key.clone())
+            .and_modify(|state| *state = update(Some(state)))
+            .or_insert_with(|| update(None));
+        Ok(())
+    }
+
+    async fn sweep(&self, last_failure_before: DateTime<Utc>) -> Result<(), Error> {
+        let now = Utc::now();
+        let last_sweep = self.last_sweep.load(Ordering::Relaxed);
+
+        if now.timestamp() - last_sweep < 60
+            || self
+                .last_sweep
+                .compare_exchange(
+                    last_sweep,
+                    now.timestamp(),
+                    Ordering::Relaxed,
+                    Ordering::Relaxed,
+                )
+                .is_err()
+        {
+            return Ok(());
+        }
+
+        self.inner.retain(|_, state| {
+            state.last_failure >= last_failure_before
+                || state.blocked_until.is_some_and(|until| until > now)
+        });
+        Ok(())
+    }
+}
+
+/// Limits failed login attempts with exponential backoff and a temporary lockout.
+///
+/// After free_attempts failures each further failure blocks the key for base_delay,
+/// doubled per failure up to max_delay. After lockout_after failures the key is
+/// locked for lockout. Failures are forgotten once none happened for reset_after.
+///
+/// # Examples
+/// ```rust
+/// use axum_session_auth::LoginLimiter;
+/// use chrono::Duration;
+///
+/// let limiter = LoginLimiter::default()
+///     .with_free_attempts(5)
+///     .with_lockout(20, Duration::try_hours(1).unwrap());
+/// ```
+///
+#[derive(Debug, Clone)]
+pub struct LoginLimiter {
+    pub(crate) store: Arc<dyn AttemptStore>,
+    pub(crate) free_attempts: u32,
+    pub(crate) base_delay: Duration,
+    pub(crate) max_delay: Duration,
+    pub(crate) lockout_after: u32,
+    pub(crate) lockout: Duration,
+    pub(crate) reset_after: Duration,
+}
+
+impl Default for LoginLimiter {
+    fn default() -> Self {
+        Self {
+            store: Arc::new(MemoryAttemptStore::default()),
+            free_attempts: 3,
+            base_delay: Duration::try_seconds(1).unwrap_or_default(),
+            max_delay: Duration::try_minutes(5).unwrap_or_default(),
+            lockout_after: 10,
+            lockout: Duration::try_minutes(15).unwrap_or_default(),
+            reset_after: Duration::try_hours(1).unwrap_or_default(),
+        }
+    }
+}
+
+impl LoginLimiter {
+    /// Set's where attempts are stored. Defaults to a MemoryAttemptStore.
+    ///
+    /// # Examples
+    /// ```rust no_run ignore
+    /// let limiter = LoginLimiter::default().with_store(Arc::new(RedisAttemptStore::new(client)));
+    /// ```
+    ///
+    #[must_use]
+    pub fn with_store(mut self, store: Arc<dyn AttemptStore>) -> Self {
+        self.store = store;
+        self
+    }
+
+    /// Set's how many failures are allowed before any delay. Defaults to 3.
+    ///
+    /// # Examples
+    /// ```rust
+    /// use axum_session_auth::LoginLimiter;
+    ///
+    /// let limiter = LoginLimiter::default().with_free_attempts(5);
+    /// ```
+    ///
+    #[must_use]
+    pub fn with_free_attempts(mut self, attempts: u32) -> Self {
+        self.free_attempts = attempts;
+        self
+    }
+
+    /// Set's the first delay and the most it can grow to. Defaults to 1 second and 5 minutes.
+    ///
+    /// # Examples
+    /// ```rust
+    /// use axum_session_auth::LoginLimiter;
+    /// use chrono::Duration;
+    ///
+    /// let limiter = LoginLimiter::default()
+    ///     .with_backoff(Duration::try_seconds(2).unwrap(), Duration::try_minutes(10).unwrap());
+    /// ```
+    ///
+    #[must_use]
+    pub fn with_backoff(mut self, base: Duration, max: Duration) -> Self {
+        self.base_delay = base;
+        self.max_delay = max;
+        self
+    }
+
+    /// Set's after how many failures a key is locked and for how long. Defaults to 10 and 15 minutes.
+    ///
+    /// # Examples
+    /// ```rust
+    /// use axum_session_auth::LoginLimiter;
+    /// use chrono::Duration;
+    ///
+    /// let limiter = LoginLimiter::default().with_lockout(20, Duration::try_hours(1).unwrap());
+    /// ```
+    ///
+    #[must_use]
+    pub fn with_lockout(mut self, after: u32, duration: Duration) -> Self {
+        self.lockout_after = after;
+        self.lockout = duration;
+        self
+    }
+
+    /// Set's how long without failures before a keys failures are forgotten. Defaults to 1 hour.
+    ///
+    /// # Examples
+    /// ```rust
+    /// use axum_session_auth::LoginLimiter;
+    /// use chrono::Duration;
+    ///
+    /// let limiter = LoginLimiter::default().with_reset_after(Duration::try_hours(24).unwrap());
+    /// ```
+    ///
+    #[must_use]
+    pub fn with_reset_after(mut self, reset_after: Duration) -> Self {
+        self.reset_after = reset_after;
+        self
+    }
+
+    /// Returns how long until the next attempt is allowed, if any of the keys is blocked.
+    pub async fn check(&self, keys: &[AttemptKey]) -> Result<Option<Duration>, Error> {
+        let now = Utc::now();
+        let mut retry_after = None;
+
+        for key in keys {
+            if let Some(until) = self.store.get(key).await?.and_then(|s| s.blocked_until) {
+                if until > now {
+                    retry_after = retry_after.max(Some(until - now));
+                }
+            }
+        }
+
+        Ok(retry_after)
+    }
+
+    /// Counts a failed attempt against every key before the credentials are checked.
+    ///
+    /// Unlike check followed by record_failure, attempts running at the same time can not
+    /// all pass before any of them is counted. Returns how long until the next attempt is
+    /// allowed instead if any of the keys is blocked, without counting the attempt.
+    /// The returned attempt stays counted as failed unless it is released.
+    ///
+    /// # Examples
+    /// ```rust no_run ignore
+    /// let attempt = match limiter.reserve(&keys).await? {
+    ///     Ok(attempt) => attempt,
+    ///     Err(retry_after) => return Err(LoginError::LockedOut { retry_after }),
+    /// };
+    ///
+    /// if verify(password) {
+    ///     attempt.release(|_| true).await?;
+    /// }
+    /// ```
+    ///
+    pub async fn reserve(
+        &self,
+        keys: &[AttemptKey],
+    ) -> Result<Result<ReservedAttempt, Duration>, Error> {
+        if let Some(retry_after) = self.check(keys).await? {
+            return Ok(Err(retry_after));
+        }
+
+        let now = Utc::now();
+        let blocked = Mutex::new(None);
+        let count = |state: Option<&AttemptState>| match state {
+            // Blocked by an attempt that was counted since check.
+            Some(state) if state.blocked_until.is_some_and(|until| until > now) => {
+                *blocked.lock().unwrap_or_else(|e| e.into_inner()) = state.blocked_until;
+                state.clone()
+            }
+            state => self.failed(state, now),
+        };
+
+        let mut reserved = ReservedAttempt {
+            limiter: self.clone(),
+            keys: Vec::with_capacity(keys.len()),
+        };
+
+        for key in keys {
+            self.store.update(key, &count).await?;
+
+            let until = blocked.lock().unwrap_or_else(|e| e.into_inner()).take();
+
+            if let Some(until) = until {
+                reserved.release(|_| false).await?;
+                return Ok(Err(until - now));
+            }
+
+            reserved.keys.push(key.clone());
+        }
+
+        self.store.sweep(now - self.reset_after).await?;
+        Ok(Ok(reserved))
+    }
+
+    /// Counts a failed attempt against every key.
+    pub async fn record_failure(&self, keys: &[AttemptKey]) -> Result<(), Error> {
+        let now = Utc::now();
+        let update = |state: Option<&AttemptState>| self.failed(state, now);
+
+        for key in keys {
+            self.store.update(key, &update).await?;
+        }
+
+        self.store.sweep(now - self.reset_after).await
+    }
+
+    /// Forgets the failed attempts of every key.
+    pub async fn record_success(&self, keys: &[AttemptKey]) -> Result<(), Error> {
+        for key in keys {
+            self.store.remove(key).await?;
+        }
+
+        Ok(())
+    }
+
+    /// Returns the state after one more failure at now.
+    fn failed(&self, state: Option<&AttemptState>, now: DateTime<Utc>) -> AttemptState {
+        let failures = match state {
+            Some(state) if now - state.last_failure < self.reset_after => state.failures + 1,
+            _ => 1,
+        };
+
+        AttemptState {
+            failures,
+            last_failure: now,
+            blocked_until: self.blocked_until(failures, now),
+        }
+    }
+
+    /// Returns the state with one failure less, for an attempt that turned out valid.
+    fn taken_back(&self, state: Option<&AttemptState>) -> AttemptState {
+        match state {
+            Some(state) => {
+                let failures = state.failures.saturating_sub(1);
+
+                AttemptState {
+                    failures,
+                    last_failure: state.last_failure,
+                    blocked_until: self.blocked_until(failures, state.last_failure),
+                }
+            }
+            None => AttemptState {
+                failures: 0,
+                last_failure: Utc::now(),
+                blocked_until: None,
+            },
+        }
+    }
+
+    /// Returns until when a key with this many failures is blocked, the last one being at.
+    fn blocked_until(&self, failures: u32, at: DateTime<Utc>) -> Option<DateTime<Utc>> {
+        if failures >= self.lockout_after {
+            Some(at + self.lockout)
+        } else if failures > self.free_attempts {
+            let doublings = (failures - self.free_attempts - 1).min(30);
+            let delay = self
+                .base_delay
+                .checked_mul(1 << doublings)
+                .map_or(self.max_delay, |delay| delay.min(self.max_delay));
+
+            Some(at + delay)
+        } else {
+            None
+        }
+    }
+}
+
+/// An attempt LoginLimiter::reserve counted as failed before the credentials were checked.
+///
+/// Dropping it leaves the failure counted. Call release when the credentials were valid.
+///
+#[derive(Debug)]
+#[must_use = "dropping a reserved attempt counts it as failed"]
+pub struct ReservedAttempt {
+    limiter: LoginLimiter,
+    keys: Vec<AttemptKey>,
+}
+
+impl ReservedAttempt {
+    /// Takes the attempt back after the credentials turned out valid.
+    ///
+    /// Keys forget returns true for lose all their failures, the others only this attempt,
+    /// so a valid login can keep earlier failures of for example its client ip counted.
+    ///
+    /// # Examples
+    /// ```rust no_run ignore
+    ///  attempt.release(|key| !matches!(key, AttemptKey::Ip(_))).await?;
+    /// ```
+    ///
+    pub async fn release(self, forget: impl Fn(&AttemptKey) -> bool) -> Result<(), Error> {
+        let limiter = &self.limiter;
+        let take_back = |state: Option<&AttemptState>| limiter.taken_back(state);
+
+        for key in &self.keys {
+            if forget(key) {
+                limiter.store.remove(key).await?;
+            } else {
+                limiter.store.update(key, &take_back).await?;
+            }
+        }
+
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::net::Ipv4Addr;
+
+    fn limiter() -> LoginLimiter {
+        LoginLimiter::default()
+            .with_free_attempts(2)
+            .with_backoff(
+                Duration::try_seconds(1).unwrap(),
+                Duration::try_seconds(4).unwrap(),
+            )
+            .with_lockout(6, Duration::try_minutes(15).unwrap())
+    }
+
+    async fn failures(limiter: &LoginLimiter, key: &AttemptKey) -> Option<u32> {
+        limiter
+            .store
+            .get(key)
+            .await
+            .unwrap()
+            .map(|state| state.failures)
+    }
+
+    #[tokio::test]
+    async fn failures_back_off_and_lock_out() {
+        let limiter = limiter();
+        let key = [AttemptKey::username("alice")];
+        let mut delays = Vec::new();
+
+        for _ in 0..6 {
+            limiter.record_failure(&key).await.unwrap();
+            delays.push(limiter.check(&key).await.unwrap().map(|d| d.num_seconds()));
+        }
+
+        // Rounded down as a little time passed since the failure.
+        assert_eq!(delays, [None, None, Some(0), Some(1), Some(3), Some(899)]);
+    }
+
+    #[tokio::test(flavor = "multi_thread", worker_threads = 4)]
+    async fn concurrent_reserves_are_all_counted() {
+        let limiter = limiter();
+        let key = AttemptKey::username("alice");
+
+        let attempts: Vec<_> = (0..16)
+            .map(|_| {
+                let limiter = limiter.clone();
+                let key = key.clone();
+                tokio::spawn(async move { limiter.reserve(&[key]).await.unwrap() })
+            })
+            .collect();
+
+        let mut allowed = Vec::new();
+
+        for attempt in attempts {
+            if let Ok(attempt) = attempt.await.unwrap() {
+                allowed.push(attempt);
+            }
+        }
+
+        // Two free attempts and the one that gets the key blocked.
+        assert_eq!(allowed.len(), 3);
+        assert_eq!(failures(&limiter, &key).await, Some(3));
+    }
+
+    #[tokio::test]
+    async fn released_attempts_keep_earlier_ip_failures() {
+        let limiter = limiter();
+        let username = AttemptKey::username("alice");
+        let ip = AttemptKey::Ip(Ipv4Addr::LOCALHOST.into());
+
+        limiter
+            .record_failure(&[username.clone(), ip.clone()])
+            .await
+            .unwrap();
+
+        let attempt = limiter
+            .reserve(&[username.clone(), ip.clone()])
+            .await
+            .unwrap()
+            .unwrap();
+        assert_eq!(failures(&limiter, &ip).await, Some(2));
+
+        attempt
+            .release(|key| !matches!(key, AttemptKey::Ip(_)))
+            .await
+            .unwrap();
+
+        assert_eq!(failures(&limiter, &username).await, None);
+        assert_eq!(failures(&limiter, &ip).await, Some(1));
+    }
+
+    #[tokio::test]
+    async fn dropped_attempts_stay_counted() {
+        let limiter = limiter();
+        let key = [AttemptKey::username("alice")];
+
+        for _ in 0..3 {
+            drop(limiter.reserve(&key).await.unwrap().unwrap());
+        }
+
+        assert!(limiter.reserve(&key).await.unwrap().is_err());
+        assert_eq!(failures(&limiter, &key[0]).await, Some(3));
+    }
+}
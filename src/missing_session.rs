@@ -0,0 +1,148 @@
+use axum_core::response::{IntoResponse, Response};
+use http::StatusCode;
+use std::{fmt, sync::Arc};
+
+/// Error for requests that reached the AuthSessionService without an axum_session `Session`.
+///
+/// This means the `SessionLayer` is missing or was added inside of the `AuthSessionLayer`.
+/// When [`MissingSession::Anonymous`] is used it is inserted into the request extensions.
+///
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct MissingSessionLayer {
+    /// Type name of the `Session` the AuthSessionService looked for.
+    pub session_type: &'static str,
+}
+
+impl fmt::Display for MissingSessionLayer {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "{} extension is not loaded. The SessionLayer must be added after the AuthSessionLayer so it runs first.",
+            self.session_type
+        )
+    }
+}
+
+impl std::error::Error for MissingSessionLayer {}
+
+impl IntoResponse for MissingSessionLayer {
+    fn into_response(self) -> Response {
+        StatusCode::INTERNAL_SERVER_ERROR.into_response()
+    }
+}
+
+/// Handler creating the response for a request without an axum_session `Session`.
+pub type MissingSessionHandler = Arc<dyn Fn(&MissingSessionLayer) -> Response + Send + Sync>;
+
+/// What the AuthSessionService does with a request without an axum_session `Session`.
+///
+/// The missing layer is logged as an error once, the first time it happens.
+///
+/// # Examples
+/// ```rust no_run ignore
+///    let layer = AuthSessionLayer::<User, i64, Sess, Pool>::new(None)
+///        .with_missing_session(MissingSession::Anonymous);
+/// ```
+///
+#[derive(Clone, Default)]
+pub enum MissingSession {
+    /// Respond with an empty 500 Internal Server Error.
+    #[default]
+    Reject,
+    /// Respond with the handler's response.
+    ///
+    /// The response body is converted with `From<axum_core::body::Body>`, which is why
+    /// AuthSessionService requires it of the inner services response body.
+    Handler(MissingSessionHandler),
+    /// Pass the request to the inner service without an AuthSession, as if anonymous.
+    ///
+    /// axum_session can only create a `Session` in its own layer, so there is no AuthSession
+    /// to insert. Handlers taking `Option<AuthSession>` get None, extracting AuthSession
+    /// directly fails with a 500 and handlers not using it work as normal.
+    Anonymous,
+}
+
+impl MissingSession {
+    /// Creates a [`MissingSession::Handler`] from a closure.
+    ///
+    /// # Examples
+    /// ```rust no_run ignore
+    ///    let missing = MissingSession::handler(|err| {
+    ///        (StatusCode::SERVICE_UNAVAILABLE, err.to_string()).into_response()
+    ///    });
+    /// ```
+    ///
+    pub fn handler<F>(handler: F) -> Self
+    where
+        F: Fn(&MissingSessionLayer) -> Response + Send + Sync + 'static,
+    {
+        MissingSession::Handler(Arc::new(handler))
+    }
+}
+
+impl fmt::Debug for MissingSession {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            MissingSession::Reject => f.write_str("Reject"),
+            MissingSession::Handler(_) => f.write_str("Handler"),
+            MissingSession::Anonymous => f.write_str("Anonymous"),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::{TestLayer, TestSession};
+    use axum_core::{
+        body::Body,
+        extract::{FromRequestParts, OptionalFromRequestParts},
+    };
+    use http::Request;
+    use std::convert::Infallible;
+    use tower::{Layer, ServiceExt};
+
+    /// Sends a request through the AuthSessionLayer alone. The inner service answers
+    /// 200 if it extracts no Option<AuthSession> and fails to extract an AuthSession.
+    async fn status(missing: MissingSession) -> StatusCode {
+        let inner = tower::service_fn(|request: Request<Body>| async move {
+            let (mut parts, _) = request.into_parts();
+            let optional =
+                <TestSession as OptionalFromRequestParts<()>>::from_request_parts(&mut parts, &())
+                    .await;
+            let required =
+                <TestSession as FromRequestParts<()>>::from_request_parts(&mut parts, &()).await;
+
+            let status = match (optional, required) {
+                (Ok(None), Err((StatusCode::INTERNAL_SERVER_ERROR, _))) => StatusCode::OK,
+                _ => StatusCode::IM_A_TEAPOT,
+            };
+
+            Ok::<_, Infallible>(status.into_response())
+        });
+
+        TestLayer::new(None)
+            .with_missing_session(missing)
+            .layer(inner)
+            .oneshot(Request::get("/").body(Body::empty()).unwrap())
+            .await
+            .unwrap()
+            .status()
+    }
+
+    #[tokio::test]
+    async fn requests_without_a_session_layer_follow_missing_session() {
+        assert_eq!(
+            status(MissingSession::Reject).await,
+            StatusCode::INTERNAL_SERVER_ERROR
+        );
+        assert_eq!(
+            status(MissingSession::handler(|_| {
+                StatusCode::SERVICE_UNAVAILABLE.into_response()
+            }))
+            .await,
+            StatusCode::SERVICE_UNAVAILABLE
+        );
+        assert_eq!(status(MissingSession::Anonymous).await, StatusCode::OK);
+    }
+}
@@ -0,0 +1,111 @@
+use crate::HasPermission;
+use chrono::{DateTime, Duration, Utc};
+use dashmap::DashMap;
+use serde::{de::DeserializeOwned, Serialize};
+use std::{collections::HashSet, fmt, hash::Hash, sync::Arc};
+
+/// A resolved permission set and when it expires.
+#[derive(Debug, Clone)]
+pub(crate) struct CachedPermissions {
+    pub(crate) permissions: Arc<HashSet<String>>,
+    pub(crate) expires: DateTime<Utc>,
+}
+
+/// Cache of resolved permission sets keyed by user id.
+///
+/// This is separate from the user cache, has its own max age and is used even
+/// if user caching is disabled. Only users whose [`HasPermission::load_permissions`]
+/// returns Some are cached, everyone else keeps using [`HasPermission::has`].
+///
+#[derive(Clone)]
+pub struct PermissionCache<Type>
+where
+    Type: Eq + Default + Clone + Send + Sync + Hash + Serialize + DeserializeOwned + 'static,
+{
+    pub(crate) inner: Arc<DashMap<Type, CachedPermissions>>,
+    pub(crate) max_age: Duration,
+}
+
+impl<Type> fmt::Debug for PermissionCache<Type>
+where
+    Type: Eq + Default + Clone + Send + Sync + Hash + Serialize + DeserializeOwned + 'static,
+{
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("PermissionCache")
+            .field("size", &self.inner.len())
+            .field("max_age", &self.max_age)
+            .finish()
+    }
+}
+
+impl<Type> PermissionCache<Type>
+where
+    Type: Eq + Default + Clone + Send + Sync + Hash + Serialize + DeserializeOwned + 'static,
+{
+    pub fn new(max_age: Duration) -> Self {
+        Self {
+            inner: Arc::new(DashMap::default()),
+            max_age,
+        }
+    }
+
+    /// Returns the cached permission set of the user, calling load_permissions if it is missing or expired.
+    ///
+    /// Returns None if the user does not support load_permissions.
+    ///
+    /// # Examples
+    /// ```rust no_run ignore
+    ///  let permissions = cache.get_or_load(&user.id, &user, &None).await;
+    /// ```
+    ///
+    pub async fn get_or_load<Pool>(
+        &self,
+        id: &Type,
+        user: &(dyn HasPermission<Pool> + Sync),
+        db: &Option<&Pool>,
+    ) -> Option<Arc<HashSet<String>>>
+    where
+        Pool: Clone + Send + Sync + fmt::Debug + 'static,
+    {
+        if let Some(cached) = self.inner.get(id) {
+            if cached.expires > Utc::now() {
+                return Some(cached.permissions.clone());
+            }
+        }
+
+        let permissions = Arc::new(user.load_permissions(db).await?);
+
+        self.inner.insert(
+            id.clone(),
+            CachedPermissions {
+                permissions: permissions.clone(),
+                expires: Utc::now() + self.max_age,
+            },
+        );
+
+        Some(permissions)
+    }
+
+    /// Removes the users permission set so it gets reloaded on next use.
+    ///
+    /// This only clears the local cache, use `AuthSession::cache_clear_permissions`
+    /// to also publish it to the invalidation bus.
+    ///
+    pub fn clear_user(&self, id: &Type) {
+        let _ = self.inner.remove(id);
+    }
+
+    /// Removes all permission sets so they get reloaded on next use.
+    ///
+    /// This only clears the local cache, use `AuthSession::cache_clear_all_permissions`
+    /// to also publish it to the invalidation bus.
+    ///
+    pub fn clear_all(&self) {
+        self.inner.clear();
+    }
+
+    /// Removes all expired permission sets.
+    pub(crate) fn sweep(&self) {
+        self.inner.retain(|_k, v| v.expires > Utc::now());
+    }
+}
@@ -0,0 +1,124 @@
+use http::{request::Parts, HeaderMap, Method, Request, Uri};
+use std::net::IpAddr;
+
+/// Parts of the request an AuthSession was created for.
+///
+/// Passed to hooks and audit events so they can see where login, logout and denied requests came from.
+/// The headers are only kept when AuthHooks are set, as only hooks can read them.
+///
+#[derive(Debug, Clone, Default)]
+pub struct RequestInfo {
+    pub method: Method,
+    pub uri: Uri,
+    pub headers: HeaderMap,
+    /// Client address read from the configured client ip header, if set and valid.
+    pub client_ip: Option<IpAddr>,
+}
+
+impl RequestInfo {
+    pub(crate) fn from_request<B>(
+        req: &Request<B>,
+        client_ip_header: Option<&str>,
+        trusted_proxies: usize,
+        keep_headers: bool,
+    ) -> Self {
+        Self {
+            method: req.method().clone(),
+            uri: req.uri().clone(),
+            headers: if keep_headers {
+                req.headers().clone()
+            } else {
+                HeaderMap::new()
+            },
+            client_ip: client_ip_header
+                .and_then(|name| client_ip(req.headers(), name, trusted_proxies)),
+        }
+    }
+}
+
+impl From<&Parts> for RequestInfo {
+    fn from(parts: &Parts) -> Self {
+        Self {
+            method: parts.method.clone(),
+            uri: parts.uri.clone(),
+            headers: parts.headers.clone(),
+            client_ip: None,
+        }
+    }
+}
+
+/// Returns the token of an `Authorization: Bearer` header.
+#[cfg(any(feature = "api-key", feature = "jwt"))]
+pub(crate) fn bearer_token(headers: &HeaderMap) -> Option<&str> {
+    let value = headers.get(http::header::AUTHORIZATION)?.to_str().ok()?;
+    let (scheme, token) = value.split_once(' ')?;
+
+    Some(token.trim()).filter(|token| scheme.eq_ignore_ascii_case("bearer") && !token.is_empty())
+}
+
+/// Percent encodes everything but the unreserved characters of RFC 3986.
+#[cfg(any(feature = "totp", feature = "oidc"))]
+pub(crate) fn percent_encode(value: &str) -> String {
+    value
+        .bytes()
+        .map(|b| match b {
+            b'A'..=b'Z' | b'a'..=b'z' | b'0'..=b'9' | b'-' | b'.' | b'_' | b'~' => {
+                (b as char).to_string()
+            }
+            _ => format!("%{:02X}", b),
+        })
+        .collect()
+}
+
+/// Reads the address added by the outermost trusted proxy from a header like X-Forwarded-For.
+fn client_ip(headers: &HeaderMap, name: &str, trusted_proxies: usize) -> Option<IpAddr> {
+    let addresses = headers
+        .get_all(name)
+        .iter()
+        .map(|value| value.to_str().ok())
+        .collect::<Option<Vec<_>>>()?;
+
+    addresses
+        .iter()
+        .flat_map(|value| value.split(','))
+        .rev()
+        .nth(trusted_proxies.saturating_sub(1))?
+        .trim()
+        .parse()
+        .ok()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn headers(values: &[&'static str]) -> HeaderMap {
+        let mut headers = HeaderMap::new();
+        for value in values {
+            headers.append("x-forwarded-for", value.parse().unwrap());
+        }
+        headers
+    }
+
+    #[test]
+    fn client_ip_ignores_addresses_sent_by_the_client() {
+        let headers = headers(&["6.6.6.6, 203.0.113.7"]);
+
+        assert_eq!(
+            client_ip(&headers, "x-forwarded-for", 1),
+            Some("203.0.113.7".parse().unwrap())
+        );
+    }
+
+    #[test]
+    fn client_ip_counts_trusted_proxies_across_header_lines() {
+        let headers = headers(&["6.6.6.6, 203.0.113.7", "10.0.0.2"]);
+
+        assert_eq!(
+            client_ip(&headers, "x-forwarded-for", 2),
+            Some("203.0.113.7".parse().unwrap())
+        );
+        assert_eq!(client_ip(&headers, "x-forwarded-for", 4), None);
+        assert_eq!(client_ip(&headers, "x-real-ip", 1), None);
+    }
+}
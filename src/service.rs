@@ -1,10 +1,14 @@
-use crate::{AuthCache, AuthConfig, AuthSession, AuthUser, Authentication};
+use crate::{
+    filter::PathFilter, AuditSink, AuthCache, AuthConfig, AuthHooks, AuthRejection, AuthRequest,
+    AuthSession, Authentication, Authenticator, Degraded, MissingSession, MissingSessionLayer,
+    RequestInfo, Scopes,
+};
 use axum_core::BoxError;
 use axum_session::{DatabasePool, Session};
 use bytes::Bytes;
 use chrono::Utc;
 use futures::future::BoxFuture;
-use http::{Request, Response};
+use http::{Extensions, Request, Response};
 use http_body::Body as HttpBody;
 use serde::{de::DeserializeOwned, Serialize};
 use std::{
@@ -12,9 +16,17 @@ use std::{
     fmt,
     hash::Hash,
     marker::PhantomData,
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc, RwLock,
+    },
     task::{Context, Poll},
 };
 use tower_service::Service;
+use tracing::{field, Instrument};
+
+/// Set once the missing SessionLayer was logged, so it is not logged for every request.
+static MISSING_SESSION_LOGGED: AtomicBool = AtomicBool::new(false);
 
 #[derive(Clone)]
 pub struct AuthSessionService<S, User, Type, Sess, Pool>
@@ -27,6 +39,11 @@ where
     pub(crate) pool: Option<Pool>,
     pub(crate) config: AuthConfig<Type>,
     pub(crate) cache: AuthCache<User, Type, Pool>,
+    pub(crate) filter: Arc<PathFilter>,
+    pub(crate) missing_session: MissingSession,
+    pub(crate) hooks: Option<Arc<dyn AuthHooks<Type, Sess>>>,
+    pub(crate) audit: Option<Arc<dyn AuditSink>>,
+    pub(crate) authenticators: Arc<[Arc<dyn Authenticator<Type, Pool>>]>,
     pub(crate) inner: S,
     pub phantom_session: PhantomData<Sess>,
 }
@@ -54,7 +71,7 @@ where
     S::Future: Send + 'static,
     ReqBody: Send + 'static,
     Infallible: From<<S as Service<Request<ReqBody>>>::Error>,
-    ResBody: HttpBody<Data = Bytes> + Default + Send + 'static,
+    ResBody: HttpBody<Data = Bytes> + Default + From<axum_core::body::Body> + Send + 'static,
     ResBody::Error: Into<BoxError>,
 {
     type Response = Response<ResBody>;
@@ -69,75 +86,220 @@ where
         let pool = self.pool.clone();
         let config = self.config.clone();
         let cache = self.cache.clone();
+        let missing_session = self.missing_session.clone();
+        let hooks = self.hooks.clone();
+        let audit = self.audit.clone();
+        let authenticators = self.authenticators.clone();
         let not_ready_inner = self.inner.clone();
         let mut ready_inner = std::mem::replace(&mut self.inner, not_ready_inner);
 
-        Box::pin(async move {
-            let axum_session = match req.extensions().get::<Session<Sess>>().cloned() {
-                Some(session) => session,
-                None => {
-                    tracing::error!("axum session extension is not loaded.");
-                    let mut res = Response::default();
-                    *res.status_mut() = http::StatusCode::INTERNAL_SERVER_ERROR;
-                    return Ok(res);
-                }
-            };
+        if self.filter.skips(req.method(), req.uri()) {
+            return Box::pin(ready_inner.call(req));
+        }
+
+        // Covers the whole request, including hooks and the inner service.
+        let span = tracing::info_span!(
+            "auth_session",
+            otel.kind = "internal",
+            user.id = field::Empty,
+            auth.cache_hit = field::Empty,
+            auth.load_ms = field::Empty,
+            auth.authenticated = field::Empty,
+            auth.mechanism = field::Empty,
+        );
+        let request_span = span.clone();
 
-            let id = axum_session
-                .get::<Type>(&config.session_id)
-                .map_or(config.anonymous_user_id.clone(), Some)
-                .unwrap_or_else(|| Type::default());
-
-            let current_user = if id != Type::default() {
-                if config.cache {
-                    if let Some(mut user) = cache.inner.get_mut(&id) {
-                        tracing::debug!("user id: {} found in cache", id);
-                        user.expires = Utc::now() + config.max_age;
-                        user.current_user.clone()
-                    } else {
-                        tracing::debug!("loading user id: {} from load_user", id);
-                        let current_user = User::load_user(id.clone(), pool.as_ref()).await.ok();
-                        let user = AuthUser::<User, Type, Pool> {
-                            current_user: current_user.clone(),
-                            expires: Utc::now() + config.max_age,
-                            phantom_pool: Default::default(),
-                            phantom_type: Default::default(),
+        Box::pin(
+            async move {
+                let axum_session = match req.extensions().get::<Session<Sess>>().cloned() {
+                    Some(session) => session,
+                    None => {
+                        let err = MissingSessionLayer {
+                            session_type: std::any::type_name::<Session<Sess>>(),
                         };
 
-                        cache.inner.insert(id.clone(), user);
-                        current_user
+                        if !MISSING_SESSION_LOGGED.swap(true, Ordering::Relaxed) {
+                            tracing::error!("{}", err);
+                        }
+
+                        return match missing_session {
+                            MissingSession::Reject => {
+                                let mut res = Response::default();
+                                *res.status_mut() = http::StatusCode::INTERNAL_SERVER_ERROR;
+                                Ok(res)
+                            }
+                            MissingSession::Handler(handler) => {
+                                Ok(handler(&err).map(ResBody::from))
+                            }
+                            MissingSession::Anonymous => {
+                                req.extensions_mut().insert(err);
+                                ready_inner.call(req).await
+                            }
+                        };
+                    }
+                };
+
+                // Only hooks can read the headers, so they are not copied for anything else.
+                let request = RequestInfo::from_request(
+                    &req,
+                    config.client_ip_header.as_deref(),
+                    config.trusted_proxies,
+                    hooks.is_some(),
+                );
+                let session_user = axum_session.get::<Type>(&config.session_id);
+                let auth_request = AuthRequest {
+                    method: req.method(),
+                    uri: req.uri(),
+                    headers: req.headers(),
+                    client_ip: request.client_ip,
+                    session_user: session_user.as_ref(),
+                    pool: pool.as_ref(),
+                    limiter: config.login_limiter.as_ref(),
+                };
+
+                // The first authenticator to recognise the request decides its user.
+                let mut authenticated = None;
+
+                for authenticator in authenticators.iter() {
+                    match authenticator.authenticate(&auth_request).await {
+                        Ok(Some(found)) => {
+                            authenticated = Some(found);
+                            break;
+                        }
+                        Ok(None) => {}
+                        Err(AuthRejection::Unauthorized(challenge)) => {
+                            return Ok(unauthorized(challenge));
+                        }
+                        Err(AuthRejection::Unavailable(err)) => {
+                            tracing::error!("{:?} failed: {}", authenticator, err);
+                            let mut res = Response::default();
+                            *res.status_mut() = http::StatusCode::SERVICE_UNAVAILABLE;
+                            return Ok(res);
+                        }
+                        Err(AuthRejection::TooManyAttempts { retry_after }) => {
+                            let mut res = Response::default();
+                            *res.status_mut() = http::StatusCode::TOO_MANY_REQUESTS;
+                            // Rounded up so clients do not retry before the block ends.
+                            let seconds = (retry_after.num_milliseconds() + 999).max(0) / 1000;
+                            res.headers_mut()
+                                .insert(http::header::RETRY_AFTER, seconds.into());
+                            return Ok(res);
+                        }
                     }
+                }
+
+                let (id, mechanism, scopes, auth_extensions, login) = match authenticated {
+                    Some(found) => {
+                        // Authenticators asking for a login log the user into the session below,
+                        // unless they already are.
+                        let login = found.login && session_user.as_ref() != Some(&found.id);
+                        // No second factor can be asked for here, so such logins are skipped
+                        // instead of leaving every request with a pending login.
+                        #[cfg(feature = "totp")]
+                        let login = login && !config.second_factor;
+
+                        (
+                            found.id,
+                            Some(found.mechanism),
+                            found.scopes,
+                            found.extensions,
+                            login,
+                        )
+                    }
+                    None => (
+                        config.anonymous_user_id.clone().unwrap_or_default(),
+                        None,
+                        Scopes::User,
+                        Extensions::new(),
+                        false,
+                    ),
+                };
+
+                if let Some(mechanism) = &mechanism {
+                    span.record("auth.mechanism", mechanism.as_str());
+                }
+
+                if id != Type::default() {
+                    span.record("user.id", field::display(&id));
+                }
+
+                // In lazy mode the user is loaded on first use of AuthSession::user.
+                let lazy_user = if config.lazy_load {
+                    None
                 } else {
-                    User::load_user(id.clone(), pool.as_ref()).await.ok()
+                    Some(cache.get_or_load_user(&id, &pool, &config).await)
+                };
+
+                let (current_user, load_error) = match &lazy_user {
+                    Some(Ok(current_user)) => (current_user.clone(), None),
+                    Some(Err(err)) => (None, Some(err.clone())),
+                    None => (None, None),
+                };
+
+                if lazy_user.is_some() {
+                    span.record(
+                        "auth.authenticated",
+                        current_user
+                            .as_ref()
+                            .is_some_and(|user| user.is_authenticated()),
+                    );
+                }
+
+                let reject = load_error.is_some() && config.degraded == Degraded::Reject;
+                let session = AuthSession {
+                    id,
+                    current_user,
+                    load_error,
+                    lazy_user: Arc::new(RwLock::new(lazy_user)),
+                    loading: Arc::default(),
+                    cache: cache.clone(),
+                    session: axum_session,
+                    request: Arc::new(request),
+                    hooks,
+                    audit,
+                    mechanism,
+                    scopes,
+                    auth_extensions: Arc::new(auth_extensions),
+                    pool,
+                    config: config.clone(),
+                    span: span.clone(),
+                };
+
+                if let (true, Some(mechanism)) = (login, &session.mechanism) {
+                    session
+                        .login_user_with_hooks(session.id.clone(), mechanism.as_str())
+                        .await;
                 }
-            } else {
-                None
-            };
 
-            // Lets clean up the cache now that we did all our user stuff.
-            if config.cache {
+                if let Some(result) = session.loaded() {
+                    session.run_load_hooks(&result).await;
+                }
+
+                if reject {
+                    let mut res = Response::default();
+                    *res.status_mut() = http::StatusCode::SERVICE_UNAVAILABLE;
+                    return Ok(res);
+                }
+
+                // Lets clean up the cache now that we did all our user stuff.
                 let last_sweep = { *cache.last_expiry_sweep.read().await };
 
                 if last_sweep <= Utc::now() {
-                    tracing::info!("clearing old users from user cache.");
-                    cache.inner.retain(|_k, v| v.expires > Utc::now());
+                    if config.cache {
+                        tracing::info!("clearing old users from user cache.");
+                        cache.sweep(&config);
+                    }
+
+                    cache.permissions.sweep();
                     *cache.last_expiry_sweep.write().await = Utc::now() + config.max_age;
                 }
-            }
 
-            let session = AuthSession {
-                id,
-                current_user,
-                cache,
-                session: axum_session,
-                pool,
-                config,
-            };
-
-            // Sets a clone of the Store in the Extensions for Direct usage and sets the Session for Direct usage
-            req.extensions_mut().insert(session);
-            ready_inner.call(req).await
-        })
+                // Sets a clone of the Store in the Extensions for Direct usage and sets the Session for Direct usage
+                req.extensions_mut().insert(session);
+                ready_inner.call(req).await
+            }
+            .instrument(request_span),
+        )
     }
 }
 
@@ -153,7 +315,289 @@ where
         f.debug_struct("AuthSessionService")
             .field("pool", &self.pool)
             .field("config", &self.config)
+            .field("filter", &self.filter)
+            .field("missing_session", &self.missing_session)
+            .field("hooks", &self.hooks)
+            .field("audit", &self.audit)
             .field("inner", &self.inner)
             .finish()
     }
 }
+
+/// 401 response for requests with invalid credentials.
+fn unauthorized<ResBody: Default>(challenge: Option<http::HeaderValue>) -> Response<ResBody> {
+    let mut res = Response::default();
+    *res.status_mut() = http::StatusCode::UNAUTHORIZED;
+
+    if let Some(challenge) = challenge {
+        res.headers_mut()
+            .insert(http::header::WWW_AUTHENTICATE, challenge);
+    }
+
+    res
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::{
+        testing::{Client, TestLayer, TestSession, User},
+        Auth, AuthMechanism, AuthRejection, AuthRequest, Authenticated, Authenticator, DenyReason,
+        Rights, SessionAuthenticator,
+    };
+    use async_trait::async_trait;
+    use http::{header, HeaderValue, Method, Request, StatusCode};
+    use std::{
+        collections::HashMap,
+        fmt,
+        sync::{
+            atomic::{AtomicU64, Ordering},
+            Arc, Mutex,
+        },
+    };
+    use tracing::{
+        field::{Field, Visit},
+        span::{Attributes, Id, Record},
+        subscriber::Subscriber,
+        Event, Metadata,
+    };
+    use tracing_core::span::Current;
+
+    type Fields = Arc<Mutex<HashMap<&'static str, String>>>;
+
+    /// Subscriber keeping the fields recorded to auth_session spans.
+    #[derive(Default)]
+    struct Recorder {
+        fields: Fields,
+        spans: Mutex<HashMap<u64, &'static Metadata<'static>>>,
+        entered: Mutex<Vec<u64>>,
+        next_id: AtomicU64,
+    }
+
+    impl Recorder {
+        fn is_auth_session(&self, span: &Id) -> bool {
+            self.spans
+                .lock()
+                .unwrap()
+                .get(&span.into_u64())
+                .is_some_and(|metadata| metadata.name() == "auth_session")
+        }
+    }
+
+    struct Visitor<'a>(&'a Fields);
+
+    impl Visit for Visitor<'_> {
+        fn record_debug(&mut self, field: &Field, value: &dyn fmt::Debug) {
+            self.0
+                .lock()
+                .unwrap()
+                .insert(field.name(), format!("{value:?}"));
+        }
+    }
+
+    impl Subscriber for Recorder {
+        fn enabled(&self, _metadata: &Metadata<'_>) -> bool {
+            true
+        }
+
+        fn new_span(&self, span: &Attributes<'_>) -> Id {
+            let id = Id::from_u64(self.next_id.fetch_add(1, Ordering::Relaxed) + 1);
+            self.spans
+                .lock()
+                .unwrap()
+                .insert(id.into_u64(), span.metadata());
+
+            if self.is_auth_session(&id) {
+                span.record(&mut Visitor(&self.fields));
+            }
+
+            id
+        }
+
+        fn record(&self, span: &Id, values: &Record<'_>) {
+            if self.is_auth_session(span) {
+                values.record(&mut Visitor(&self.fields));
+            }
+        }
+
+        fn record_follows_from(&self, _span: &Id, _follows: &Id) {}
+
+        fn event(&self, _event: &Event<'_>) {}
+
+        fn enter(&self, span: &Id) {
+            self.entered.lock().unwrap().push(span.into_u64());
+        }
+
+        fn exit(&self, _span: &Id) {
+            self.entered.lock().unwrap().pop();
+        }
+
+        fn current_span(&self) -> Current {
+            let entered = self.entered.lock().unwrap().last().copied();
+
+            match entered.and_then(|id| Some((id, *self.spans.lock().unwrap().get(&id)?))) {
+                Some((id, metadata)) => Current::new(Id::from_u64(id), metadata),
+                None => Current::none(),
+            }
+        }
+    }
+
+    #[tokio::test]
+    async fn auth_session_spans_record_the_user_and_cache_lookup() {
+        let client = Client::new(TestLayer::new(None)).await;
+        client.get(|auth| async move { auth.login_user(60) }).await;
+
+        let recorder = Recorder::default();
+        let fields = recorder.fields.clone();
+        let _guard = tracing::subscriber::set_default(recorder);
+
+        client.get(|_| async {}).await;
+
+        let fields = fields.lock().unwrap().clone();
+        assert_eq!(fields.get("user.id").map(String::as_str), Some("60"));
+        assert_eq!(
+            fields.get("auth.cache_hit").map(String::as_str),
+            Some("false")
+        );
+        assert_eq!(
+            fields.get("auth.authenticated").map(String::as_str),
+            Some("true")
+        );
+        assert!(fields.contains_key("auth.load_ms"));
+        assert_eq!(
+            fields.get("otel.kind").map(String::as_str),
+            Some("\"internal\"")
+        );
+    }
+
+    /// Authenticator acting on its header, like `x-first: user 80`.
+    #[derive(Debug)]
+    struct Scripted(&'static str);
+
+    #[async_trait]
+    impl Authenticator<i64, ()> for Scripted {
+        async fn authenticate(
+            &self,
+            request: &AuthRequest<'_, i64, ()>,
+        ) -> Result<Option<Authenticated<i64>>, AuthRejection> {
+            let Some(value) = request.headers.get(self.0).and_then(|v| v.to_str().ok()) else {
+                return Ok(None);
+            };
+
+            match value.split_once(' ') {
+                Some(("user", id)) => Ok(Some(Authenticated::new(
+                    id.parse().unwrap(),
+                    AuthMechanism::Custom(self.0.into()),
+                ))),
+                Some(("deny", challenge)) => Err(AuthRejection::Unauthorized(Some(
+                    HeaderValue::from_str(challenge).unwrap(),
+                ))),
+                Some(("limit", millis)) => Err(AuthRejection::TooManyAttempts {
+                    retry_after: chrono::Duration::try_milliseconds(millis.parse().unwrap())
+                        .unwrap(),
+                }),
+                _ => Err(AuthRejection::Unavailable(anyhow::anyhow!("store is down"))),
+            }
+        }
+    }
+
+    async fn chain() -> Client {
+        Client::new(TestLayer::new(None).with_authenticators([
+            Arc::new(Scripted("x-first")) as Arc<dyn Authenticator<i64, ()>>,
+            Arc::new(Scripted("x-second")),
+            Arc::new(SessionAuthenticator),
+        ]))
+        .await
+    }
+
+    #[tokio::test]
+    async fn the_first_authenticator_to_recognise_a_request_wins() {
+        let client = chain().await;
+        client.get(|auth| async move { auth.login_user(80) }).await;
+
+        let mechanism = |auth: TestSession| async move { (auth.id, auth.mechanism.clone()) };
+
+        let (_, result) = client.get(mechanism).await;
+        assert_eq!(result, Some((80, Some(AuthMechanism::Session))));
+
+        let (_, result) = client
+            .send(Request::get("/").header("x-second", "user 82"), mechanism)
+            .await;
+        assert_eq!(
+            result,
+            Some((82, Some(AuthMechanism::Custom("x-second".into()))))
+        );
+
+        let (_, result) = client
+            .send(
+                Request::get("/")
+                    .header("x-first", "user 81")
+                    .header("x-second", "user 82"),
+                mechanism,
+            )
+            .await;
+        assert_eq!(
+            result,
+            Some((81, Some(AuthMechanism::Custom("x-first".into()))))
+        );
+
+        // A rejection ends the chain, even if a later authenticator would accept.
+        let (response, result) = client
+            .send(
+                Request::get("/")
+                    .header("x-first", "deny Test realm=\"api\"")
+                    .header("x-second", "user 82"),
+                mechanism,
+            )
+            .await;
+        assert_eq!(response.status(), StatusCode::UNAUTHORIZED);
+        assert_eq!(
+            response.headers().get(header::WWW_AUTHENTICATE).unwrap(),
+            "Test realm=\"api\""
+        );
+        assert_eq!(result, None);
+    }
+
+    #[tokio::test]
+    async fn rejections_map_to_their_responses() {
+        let client = chain().await;
+
+        let (response, result) = client
+            .send(
+                Request::get("/").header("x-first", "limit 1500"),
+                |_| async {},
+            )
+            .await;
+        assert_eq!(response.status(), StatusCode::TOO_MANY_REQUESTS);
+        // Rounded up, so clients do not retry too early.
+        assert_eq!(response.headers().get(header::RETRY_AFTER).unwrap(), "2");
+        assert_eq!(result, None);
+
+        let (response, result) = client
+            .send(Request::get("/").header("x-second", "down"), |_| async {})
+            .await;
+        assert_eq!(response.status(), StatusCode::SERVICE_UNAVAILABLE);
+        assert_eq!(result, None);
+    }
+
+    #[tokio::test]
+    async fn guards_can_require_a_mechanism() {
+        let client = chain().await;
+        client.get(|auth| async move { auth.login_user(83) }).await;
+
+        let authorize = |auth: TestSession| async move {
+            let mut guard = Auth::<User, i64, ()>::build([Method::GET], true);
+            guard
+                .requires(Rights::permission("read"))
+                .mechanisms([AuthMechanism::Session]);
+            auth.authorize(&guard, &Method::GET).await
+        };
+
+        let (_, result) = client.get(authorize).await;
+        assert_eq!(result, Some(Ok(())));
+
+        let (_, result) = client
+            .send(Request::get("/").header("x-first", "user 83"), authorize)
+            .await;
+        assert_eq!(result, Some(Err(DenyReason::Mechanism)));
+    }
+}
@@ -1,15 +1,37 @@
-#[cfg(feature = "advanced")]
-use crate::AuthUser;
-use crate::{AuthCache, AuthConfig};
+#[cfg(any(feature = "credentials", feature = "totp"))]
+use crate::AttemptKey;
+#[cfg(feature = "oidc")]
+use crate::{oidc::OidcFlow, OidcCallback, OidcClient, OidcError};
+use crate::{
+    telemetry, AuditEvent, AuditKind, AuditSink, Auth, AuthCache, AuthConfig, AuthHooks,
+    AuthMechanism, DenyReason, HasPermission, MissingSessionLayer, RequestInfo, Scopes,
+};
+#[cfg(feature = "totp")]
+use crate::{
+    totp::{constant_time_eq, hash_recovery_code},
+    SecondFactorError, TotpStore,
+};
+#[cfg(feature = "credentials")]
+use crate::{CredentialStore, LoginError};
+#[cfg(feature = "one-time-token")]
+use crate::{OneTimeTokens, TokenError};
 use anyhow::Error;
 use async_trait::async_trait;
-use axum_core::extract::FromRequestParts;
+use axum_core::extract::{FromRequestParts, OptionalFromRequestParts};
 use axum_session::{DatabasePool, Session};
-#[cfg(feature = "advanced")]
-use chrono::Utc;
-use http::{request::Parts, StatusCode};
-use serde::{de::DeserializeOwned, Serialize};
-use std::{fmt, hash::Hash};
+use chrono::{DateTime, Duration, Utc};
+use http::{request::Parts, Extensions, Method, StatusCode};
+use serde::{de::DeserializeOwned, Deserialize, Serialize};
+use std::{
+    fmt,
+    hash::Hash,
+    sync::{Arc, RwLock},
+};
+use tokio::sync::Mutex;
+use tracing::{field, Instrument};
+
+/// The result of loading the user of an AuthSession.
+pub(crate) type LoadResult<User> = Result<Option<User>, Arc<Error>>;
 
 /// AuthSession that is generated when a user is routed via Axum
 ///
@@ -24,13 +46,35 @@ where
     Sess: DatabasePool + Clone + fmt::Debug + Sync + Send + 'static,
 {
     pub id: Type,
+    /// The user loaded when the AuthSession was created, or by reload_user on this
+    /// AuthSession. Always None in lazy mode, where user() loads the user instead.
     pub current_user: Option<User>,
+    /// Set when load_user failed with a transient error. The user is then
+    /// treated as not loaded, so handlers can check this to respond with a 503 instead.
+    /// Like current_user this is not set by user(), which returns the error itself.
+    pub load_error: Option<Arc<Error>>,
     pub session: Session<Sess>,
+    /// Parts of the request this AuthSession was created for.
+    pub request: Arc<RequestInfo>,
+    pub(crate) hooks: Option<Arc<dyn AuthHooks<Type, Sess>>>,
+    pub(crate) audit: Option<Arc<dyn AuditSink>>,
+    /// The mechanism that authenticated the request, None for anonymous requests.
+    pub mechanism: Option<AuthMechanism>,
+    /// How the authenticating mechanism scopes the users permissions.
+    pub(crate) scopes: Scopes,
+    /// Mechanism specific data set by the authenticating Authenticator.
+    pub auth_extensions: Arc<Extensions>,
+    /// The user loaded by the service, the first call to user or reload_user, shared between clones.
+    pub(crate) lazy_user: Arc<RwLock<Option<LoadResult<User>>>>,
+    /// Held while the user is loaded, so clones calling user at once load them only once.
+    pub(crate) loading: Arc<Mutex<()>>,
     pub(crate) cache: AuthCache<User, Type, Pool>,
     #[allow(dead_code)]
     pub(crate) pool: Option<Pool>,
     #[allow(dead_code)]
     pub(crate) config: AuthConfig<Type>,
+    /// The auth_session span of the request, to record lazily loaded users to.
+    pub(crate) span: tracing::Span,
 }
 
 #[async_trait]
@@ -41,11 +85,94 @@ where
     Type: Eq + Default + Clone + Send + Sync + Hash + Serialize + DeserializeOwned + 'static,
 {
     async fn load_user(userid: Type, pool: Option<&Pool>) -> Result<User, Error>;
+
+    /// Returns the generation of this user, such as an updated_at timestamp or a counter.
+    ///
+    /// When this returns Some the cache compares it to current_version on each
+    /// request and reloads the user when they differ. Returns None by default.
+    fn version(&self) -> Option<i64> {
+        None
+    }
+
+    /// Returns the stored generation of the user. Should be much cheaper than load_user.
+    ///
+    /// Only called for users whose version returns Some. Returns None by default.
+    async fn current_version(_userid: Type, _pool: Option<&Pool>) -> Result<Option<i64>, Error> {
+        Ok(None)
+    }
+
+    /// Classifies an error returned by load_user.
+    ///
+    /// By default only [`UserNotFound`] errors are treated as [`LoadFailure::NotFound`],
+    /// everything else is [`LoadFailure::Transient`].
+    fn load_failure(error: &Error) -> LoadFailure {
+        if error.is::<UserNotFound>() {
+            LoadFailure::NotFound
+        } else {
+            LoadFailure::Transient
+        }
+    }
+
     fn is_authenticated(&self) -> bool;
     fn is_active(&self) -> bool;
     fn is_anonymous(&self) -> bool;
 }
 
+/// How a failed load_user call is handled.
+///
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum LoadFailure {
+    /// The user does not exist. This is cached for the not found max age
+    /// and the user is treated as not logged in.
+    NotFound,
+    /// The user could not be loaded right now, for example the database is down.
+    /// This is not cached and is set as the AuthSession's load_error.
+    Transient,
+}
+
+/// Error load_user fails with when it takes longer than the configured load timeout.
+///
+/// This is a [`LoadFailure::Transient`] error with the default load_failure.
+///
+#[derive(Debug, Clone, Copy, Default)]
+pub struct LoadTimeout;
+
+impl fmt::Display for LoadTimeout {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str("load_user timed out")
+    }
+}
+
+impl std::error::Error for LoadTimeout {}
+
+/// Error to return from load_user when the user does not exist.
+///
+/// # Examples
+/// ```rust no_run ignore
+///  return Err(UserNotFound.into());
+/// ```
+///
+#[derive(Debug, Clone, Copy, Default)]
+pub struct UserNotFound;
+
+impl fmt::Display for UserNotFound {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str("user not found")
+    }
+}
+
+impl std::error::Error for UserNotFound {}
+
+/// When and how the current user logged in, stored in the session by login_user.
+///
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct LoginInfo {
+    /// Time of the login or the last reauthenticate call.
+    pub at: DateTime<Utc>,
+    /// How the user logged in, like `password` or `password+totp`.
+    pub method: String,
+}
+
 impl<S, User, Type, Sess, Pool> FromRequestParts<S> for AuthSession<User, Type, Sess, Pool>
 where
     User: Authentication<User, Type, Pool> + Clone + Send + Sync + 'static,
@@ -57,6 +184,13 @@ where
     type Rejection = (http::StatusCode, &'static str);
 
     async fn from_request_parts(parts: &mut Parts, _state: &S) -> Result<Self, Self::Rejection> {
+        if parts.extensions.get::<MissingSessionLayer>().is_some() {
+            return Err((
+                StatusCode::INTERNAL_SERVER_ERROR,
+                "Can't extract AuthSession. The axum_session `SessionLayer` did not run before `AuthSessionLayer`.",
+            ));
+        }
+
         parts
             .extensions
             .get::<AuthSession<User, Type, Sess, Pool>>()
@@ -68,6 +202,31 @@ where
     }
 }
 
+/// Extracts None for requests passed on by [`MissingSession::Anonymous`](crate::MissingSession::Anonymous).
+impl<S, User, Type, Sess, Pool> OptionalFromRequestParts<S> for AuthSession<User, Type, Sess, Pool>
+where
+    User: Authentication<User, Type, Pool> + Clone + Send + Sync + 'static,
+    Pool: Clone + Send + Sync + fmt::Debug + 'static,
+    Type: Eq + Default + Clone + Send + Sync + Hash + Serialize + DeserializeOwned + 'static,
+    Sess: DatabasePool + Clone + fmt::Debug + Sync + Send + 'static,
+    S: Send + Sync,
+{
+    type Rejection = (http::StatusCode, &'static str);
+
+    async fn from_request_parts(
+        parts: &mut Parts,
+        state: &S,
+    ) -> Result<Option<Self>, Self::Rejection> {
+        if parts.extensions.get::<MissingSessionLayer>().is_some() {
+            return Ok(None);
+        }
+
+        <Self as FromRequestParts<S>>::from_request_parts(parts, state)
+            .await
+            .map(Some)
+    }
+}
+
 impl<User, Type, Sess, Pool> AuthSession<User, Type, Sess, Pool>
 where
     User: Authentication<User, Type, Pool> + Clone + Send,
@@ -75,18 +234,35 @@ where
     Type: Eq + Default + Clone + Send + Sync + Hash + Serialize + DeserializeOwned + 'static,
     Sess: DatabasePool + Clone + fmt::Debug + Sync + Send + 'static,
 {
+    /// Returns the result of loading the user, or None if it was not loaded yet.
+    pub(crate) fn loaded(&self) -> Option<LoadResult<User>> {
+        self.lazy_user
+            .read()
+            .unwrap_or_else(|e| e.into_inner())
+            .clone()
+    }
+
+    /// Calls f with the loaded user shared by all clones, or None if there is none yet.
+    fn with_loaded_user<R>(&self, f: impl FnOnce(Option<&User>) -> R) -> R {
+        let loaded = self.lazy_user.read().unwrap_or_else(|e| e.into_inner());
+
+        f(loaded
+            .as_ref()
+            .and_then(|user| user.as_ref().ok())
+            .and_then(Option::as_ref))
+    }
+
     /// Checks if the user is Authenticated
     ///
+    /// In lazy mode this is false until the user is loaded by calling user().
+    ///
     /// # Examples
     /// ```rust no_run ignore
     ///  auth.is_authenticated();
     /// ```
     ///
     pub fn is_authenticated(&self) -> bool {
-        match &self.current_user {
-            Some(n) => n.is_authenticated(),
-            None => false,
-        }
+        self.with_loaded_user(|user| user.is_some_and(|n| n.is_authenticated()))
     }
 
     /// Checks if the user is Active
@@ -97,10 +273,7 @@ where
     /// ```
     ///
     pub fn is_active(&self) -> bool {
-        match &self.current_user {
-            Some(n) => n.is_active(),
-            None => false,
-        }
+        self.with_loaded_user(|user| user.is_some_and(|n| n.is_active()))
     }
 
     /// Checks if the user is Anonymous
@@ -111,10 +284,7 @@ where
     /// ```
     ///
     pub fn is_anonymous(&self) -> bool {
-        match &self.current_user {
-            Some(n) => n.is_anonymous(),
-            None => true,
-        }
+        self.with_loaded_user(|user| user.is_none_or(|n| n.is_anonymous()))
     }
 
     /// Sets the Session Data to be saved for Long Term
@@ -130,40 +300,670 @@ where
 
     /// Sets the user id into the Session so it can auto login the user upon Axum request.
     ///
+    /// The login is recorded as LoginInfo with the method `direct`, use
+    /// login_user_with_method to record how the user proved who they are.
+    ///
+    /// With the `totp` feature and `AuthConfig::with_second_factor` set, the user is instead
+    /// put into a pending second factor state and stays unauthenticated until verify_totp,
+    /// verify_recovery_code or complete_login is called.
+    ///
+    /// The on_login hook is not run, use login_user_with_hooks for that.
+    ///
     /// # Examples
     /// ```rust no_run ignore
     ///  auth.login_user(user.id);
     /// ```
     ///
     pub fn login_user(&self, id: Type) {
-        self.session.set(&self.config.session_id, id);
+        self.login_user_with_method(id, "direct");
+    }
+
+    /// Logs the user in like login_user, recording the authentication method in LoginInfo.
+    ///
+    /// # Examples
+    /// ```rust no_run ignore
+    ///  auth.login_user_with_method(user.id, "webauthn");
+    /// ```
+    ///
+    pub fn login_user_with_method(&self, id: Type, method: impl Into<String>) {
+        self.start_login(id, method.into());
+    }
+
+    /// Logs the user in like login_user_with_method, then awaits the on_login hook.
+    ///
+    /// The hook is not run while the login waits for a second factor, but once it completes.
+    ///
+    /// # Examples
+    /// ```rust no_run ignore
+    ///  auth.login_user_with_hooks(user.id, "password").await;
+    /// ```
+    ///
+    pub async fn login_user_with_hooks(&self, id: Type, method: impl Into<String>) {
+        if self.start_login(id.clone(), method.into()) {
+            self.login_hook(&id).await;
+        }
+    }
+
+    /// Logs the user in or puts them into the pending second factor state.
+    /// Returns false if the login is pending.
+    fn start_login(&self, id: Type, method: String) -> bool {
+        #[cfg(feature = "totp")]
+        if self.config.second_factor {
+            self.session.remove(&self.config.session_id);
+            self.session
+                .set(&self.pending_key(), (id, Utc::now().timestamp(), method));
+            self.session.renew();
+            return false;
+        }
+
+        self.finish_login(id, method);
+        true
+    }
+
+    /// Sets the user id and LoginInfo into the Session and runs the login telemetry and audit.
+    fn finish_login(&self, id: Type, method: String) {
+        self.session.set(&self.config.session_id, id.clone());
+        self.session.set(
+            &self.login_info_key(),
+            LoginInfo {
+                at: Utc::now(),
+                method,
+            },
+        );
         self.session.renew();
+        telemetry::login();
+        self.audit(AuditKind::Login, &id);
+    }
+
+    /// Awaits the on_login hook, if hooks are set.
+    async fn login_hook(&self, id: &Type) {
+        if let Some(hooks) = &self.hooks {
+            hooks.on_login(id, &self.request, &self.session).await;
+        }
+    }
+
+    fn login_info_key(&self) -> String {
+        format!("{}_login_info", self.config.session_id)
+    }
+
+    /// Returns when and how the current user logged in.
+    ///
+    /// # Examples
+    /// ```rust no_run ignore
+    ///  let method = auth.login_info().map(|info| info.method);
+    /// ```
+    ///
+    pub fn login_info(&self) -> Option<LoginInfo> {
+        self.session.get(&self.login_info_key())
+    }
+
+    /// Returns the LoginInfo only if this request was authenticated by the session, as its user.
+    ///
+    /// API keys, JWTs and Basic auth requests never count as a fresh login, even if the
+    /// session holds a recent login of some user.
+    pub(crate) fn session_login_info(&self) -> Option<LoginInfo> {
+        if self.mechanism != Some(AuthMechanism::Session)
+            || self.session.get::<Type>(&self.config.session_id).as_ref() != Some(&self.id)
+        {
+            return None;
+        }
+
+        self.login_info()
+    }
+
+    /// Returns the API key the request authenticated with, if any.
+    ///
+    /// # Examples
+    /// ```rust no_run ignore
+    ///  let scopes = auth.api_key().map(|key| key.scopes.clone());
+    /// ```
+    ///
+    #[cfg(feature = "api-key")]
+    pub fn api_key(&self) -> Option<&crate::ApiKey<Type>> {
+        self.auth_extensions.get()
+    }
+
+    /// Returns the verified JWT the request authenticated with, if any.
+    ///
+    /// # Examples
+    /// ```rust no_run ignore
+    ///  let subject = auth.jwt().map(|claims| claims.id);
+    /// ```
+    ///
+    #[cfg(feature = "jwt")]
+    pub fn jwt(&self) -> Option<&crate::JwtClaims<Type>> {
+        self.auth_extensions.get()
+    }
+
+    /// Returns true if the user logged in or reauthenticated within the duration.
+    ///
+    /// Always false for requests not authenticated by the session.
+    ///
+    /// # Examples
+    /// ```rust no_run ignore
+    ///  if !auth.is_fresh(Duration::try_minutes(10).unwrap()) {
+    ///      return Redirect::to("/reauthenticate").into_response();
+    ///  }
+    /// ```
+    ///
+    pub fn is_fresh(&self, within: Duration) -> bool {
+        self.session_login_info()
+            .is_some_and(|info| Utc::now() - info.at <= within)
+    }
+
+    /// Marks the login as fresh again, after the user proved who they are once more,
+    /// like by entering their password. Does nothing if no one is logged in.
+    ///
+    /// # Examples
+    /// ```rust no_run ignore
+    ///  if hashing.verify(&form.password, &user.password_hash)? {
+    ///      auth.reauthenticate();
+    ///  }
+    /// ```
+    ///
+    pub fn reauthenticate(&self) {
+        if let Some(mut info) = self.login_info() {
+            info.at = Utc::now();
+            self.session.set(&self.login_info_key(), info);
+        }
+    }
+
+    #[cfg(feature = "totp")]
+    fn pending_key(&self) -> String {
+        format!("{}_pending_second_factor", self.config.session_id)
+    }
+
+    /// Returns the id of the user waiting for a second factor, unless it expired.
+    ///
+    /// # Examples
+    /// ```rust no_run ignore
+    ///  if auth.pending_user_id().is_some() {
+    ///      return Redirect::to("/login/totp").into_response();
+    ///  }
+    /// ```
+    ///
+    #[cfg(feature = "totp")]
+    pub fn pending_user_id(&self) -> Option<Type> {
+        self.pending_login().ok().map(|(id, _)| id)
+    }
+
+    #[cfg(feature = "totp")]
+    fn pending_login(&self) -> Result<(Type, String), SecondFactorError> {
+        let (id, since, method) = self
+            .session
+            .get::<(Type, i64, String)>(&self.pending_key())
+            .ok_or(SecondFactorError::NoPendingLogin)?;
+
+        if Utc::now().timestamp() - since > self.config.second_factor_timeout.num_seconds() {
+            self.session.remove(&self.pending_key());
+            return Err(SecondFactorError::Expired);
+        }
+
+        Ok((id, method))
+    }
+
+    /// Counts a code against the pending user before it is checked, in the LoginLimiter
+    /// so the count outlives the pending login. Dropping the returned attempt leaves it counted.
+    #[cfg(feature = "totp")]
+    async fn reserve_code(
+        &self,
+        id: &Type,
+    ) -> Result<Option<crate::ReservedAttempt>, SecondFactorError> {
+        let Some(limiter) = &self.config.login_limiter else {
+            return Ok(None);
+        };
+
+        match limiter
+            .reserve(&[AttemptKey::user(id)])
+            .await
+            .map_err(SecondFactorError::Store)?
+        {
+            Ok(attempt) => Ok(Some(attempt)),
+            Err(retry_after) => Err(SecondFactorError::TooManyAttempts { retry_after }),
+        }
+    }
+
+    /// Forgets the failures of the pending user once a code was accepted, then logs them in.
+    #[cfg(feature = "totp")]
+    async fn accept_code(
+        &self,
+        attempt: Option<crate::ReservedAttempt>,
+        factor: &str,
+    ) -> Result<Type, SecondFactorError> {
+        if let Some(attempt) = attempt {
+            attempt
+                .release(|_| true)
+                .await
+                .map_err(SecondFactorError::Store)?;
+        }
+
+        self.complete_pending(Some(factor)).await
+    }
+
+    /// Logs in the pending user, adding the second factor to the login method.
+    #[cfg(feature = "totp")]
+    async fn complete_pending(&self, factor: Option<&str>) -> Result<Type, SecondFactorError> {
+        let (id, method) = self.pending_login()?;
+        let method = match factor {
+            Some(factor) => format!("{}+{}", method, factor),
+            None => method,
+        };
+
+        self.session.remove(&self.pending_key());
+        self.finish_login(id.clone(), method);
+        self.login_hook(&id).await;
+        Ok(id)
+    }
+
+    /// Logs in the pending user without checking a second factor.
+    ///
+    /// Use this for users that have not enrolled a second factor. The on_login hook is
+    /// awaited before this returns.
+    ///
+    /// # Examples
+    /// ```rust no_run ignore
+    ///  if !user.totp_enabled {
+    ///      auth.complete_login().await?;
+    ///  }
+    /// ```
+    ///
+    #[cfg(feature = "totp")]
+    pub async fn complete_login(&self) -> Result<Type, SecondFactorError> {
+        self.complete_pending(None).await
+    }
+
+    /// Checks a TOTP code for the pending user and logs them in if it is valid.
+    ///
+    /// The accepted step is saved with save_last_step so the code can not be used again.
+    /// Codes are counted against the user id in the configured LoginLimiter before they are
+    /// checked, and only forgotten once a code is accepted. Blocked users get TooManyAttempts.
+    ///
+    /// # Examples
+    /// ```rust no_run ignore
+    /// match auth.verify_totp(&second_factors, &form.code).await {
+    ///     Ok(_) => Redirect::to("/").into_response(),
+    ///     Err(SecondFactorError::InvalidCode) => StatusCode::UNAUTHORIZED.into_response(),
+    ///     Err(_) => Redirect::to("/login").into_response(),
+    /// }
+    /// ```
+    ///
+    #[cfg(feature = "totp")]
+    pub async fn verify_totp<S>(&self, store: &S, code: &str) -> Result<Type, SecondFactorError>
+    where
+        S: TotpStore<Type, Pool>,
+    {
+        let (id, _) = self.pending_login()?;
+        let attempt = self.reserve_code(&id).await?;
+        let factor = store
+            .load(&id, self.pool.as_ref())
+            .await
+            .map_err(SecondFactorError::Store)?
+            .ok_or(SecondFactorError::NotEnrolled)?;
+
+        let Some(step) = factor.totp.verify(code, factor.last_step) else {
+            return Err(SecondFactorError::InvalidCode);
+        };
+
+        // Another request may have used this code since it was loaded.
+        if !store
+            .save_last_step(&id, step, self.pool.as_ref())
+            .await
+            .map_err(SecondFactorError::Store)?
+        {
+            return Err(SecondFactorError::InvalidCode);
+        }
+
+        self.accept_code(attempt, "totp").await
+    }
+
+    /// Checks a recovery code for the pending user, removes it and logs them in if it is valid.
+    ///
+    /// Codes are counted against the user id like in verify_totp.
+    ///
+    /// # Examples
+    /// ```rust no_run ignore
+    ///  auth.verify_recovery_code(&second_factors, &form.code).await?;
+    /// ```
+    ///
+    #[cfg(feature = "totp")]
+    pub async fn verify_recovery_code<S>(
+        &self,
+        store: &S,
+        code: &str,
+    ) -> Result<Type, SecondFactorError>
+    where
+        S: TotpStore<Type, Pool>,
+    {
+        let (id, _) = self.pending_login()?;
+        let attempt = self.reserve_code(&id).await?;
+        let factor = store
+            .load(&id, self.pool.as_ref())
+            .await
+            .map_err(SecondFactorError::Store)?
+            .ok_or(SecondFactorError::NotEnrolled)?;
+
+        let hash = hash_recovery_code(code);
+        let found = factor.recovery_codes.iter().fold(false, |found, stored| {
+            constant_time_eq(stored.as_bytes(), hash.as_bytes()) | found
+        });
+
+        if !found
+            || !store
+                .remove_recovery_code(&id, &hash, self.pool.as_ref())
+                .await
+                .map_err(SecondFactorError::Store)?
+        {
+            return Err(SecondFactorError::InvalidCode);
+        }
+
+        self.accept_code(attempt, "recovery_code").await
+    }
+
+    /// Looks the user up by username, verifies the password and logs them in with
+    /// login_user_with_hooks.
+    ///
+    /// Unknown usernames still hash the password so they take as long as a wrong password.
+    /// If a LoginLimiter is configured, attempts are counted against the username, user id
+    /// and client ip before the password is checked and taken back when it is valid. Blocked
+    /// attempts return LockedOut without checking the password.
+    /// If the stored hash used other parameters than the configured PasswordHashing it is
+    /// rehashed and saved with update_hash; a failure there is logged and does not stop the login.
+    ///
+    /// # Examples
+    /// ```rust no_run ignore
+    /// match auth.login_with_password(&users, &form.username, &form.password).await {
+    ///     Ok(_) => Redirect::to("/").into_response(),
+    ///     Err(LoginError::InvalidCredentials) => StatusCode::UNAUTHORIZED.into_response(),
+    ///     Err(_) => StatusCode::INTERNAL_SERVER_ERROR.into_response(),
+    /// }
+    /// ```
+    ///
+    #[cfg(feature = "credentials")]
+    pub async fn login_with_password<S>(
+        &self,
+        store: &S,
+        username: &str,
+        password: &str,
+    ) -> Result<Type, LoginError>
+    where
+        S: CredentialStore<Type, Pool>,
+    {
+        let limiter = self.config.login_limiter.as_ref();
+        let mut keys = vec![AttemptKey::username(username)];
+
+        if let Some(ip) = self.request.client_ip {
+            keys.push(AttemptKey::Ip(ip));
+        }
+
+        // Attempts are counted as failed before the password is checked, so a burst of
+        // requests can not all be checked before the first failure is recorded.
+        let mut attempts = Vec::new();
+
+        if let Some(limiter) = limiter {
+            match limiter.reserve(&keys).await.map_err(LoginError::Store)? {
+                Ok(attempt) => attempts.push(attempt),
+                Err(retry_after) => return Err(LoginError::LockedOut { retry_after }),
+            }
+        }
+
+        let credentials = store
+            .lookup(username, self.pool.as_ref())
+            .await
+            .map_err(LoginError::Store)?;
+
+        if let (Some(limiter), Some(credentials)) = (limiter, &credentials) {
+            let key = AttemptKey::user(&credentials.id);
+
+            match limiter
+                .reserve(std::slice::from_ref(&key))
+                .await
+                .map_err(LoginError::Store)?
+            {
+                Ok(attempt) => attempts.push(attempt),
+                Err(retry_after) => return Err(LoginError::LockedOut { retry_after }),
+            }
+        }
+
+        let hashing = self.config.password_hashing.clone();
+        let password = password.to_owned();
+        let hash = credentials.as_ref().map(|c| c.hash.clone());
+
+        let (verified, rehash) = tokio::task::spawn_blocking(move || {
+            let Some(hash) = hash else {
+                let _ = hashing.hash(&password);
+                return Ok((false, None));
+            };
+
+            if !hashing.verify(&password, &hash)? {
+                return Ok((false, None));
+            }
+
+            let rehash = if hashing.needs_rehash(&hash) {
+                Some(hashing.hash(&password)?)
+            } else {
+                None
+            };
+
+            Ok::<_, argon2::password_hash::Error>((true, rehash))
+        })
+        .await
+        .map_err(|e| LoginError::Store(e.into()))??;
+
+        // Dropping the reserved attempts leaves them counted as failed.
+        let Some(credentials) = credentials.filter(|_| verified) else {
+            return Err(LoginError::InvalidCredentials);
+        };
+
+        // The ip is kept so one valid account can not reset the limit for a whole ip. With a
+        // second factor the user id keeps its failures until a code was accepted as well.
+        let forget = |key: &AttemptKey| match key {
+            AttemptKey::Ip(_) => false,
+            #[cfg(feature = "totp")]
+            AttemptKey::User(_) => !self.config.second_factor,
+            _ => true,
+        };
+
+        for attempt in attempts {
+            attempt.release(forget).await.map_err(LoginError::Store)?;
+        }
+
+        if let Some(hash) = rehash {
+            if let Err(err) = store
+                .update_hash(&credentials.id, hash, self.pool.as_ref())
+                .await
+            {
+                tracing::warn!("failed to store rehashed password: {}", err);
+            }
+        }
+
+        self.login_user_with_hooks(credentials.id.clone(), "password")
+            .await;
+        Ok(credentials.id)
+    }
+
+    /// Consumes a login token from OneTimeTokens::issue_login and logs its user in with
+    /// login_user_with_hooks.
+    ///
+    /// The token is removed from the store first, so it can not be used again even if
+    /// it turns out to be expired.
+    ///
+    /// # Examples
+    /// ```rust no_run ignore
+    /// match auth.login_with_token(&tokens, &token).await {
+    ///     Ok(_) => Redirect::to("/").into_response(),
+    ///     Err(TokenError::Store(_)) => StatusCode::SERVICE_UNAVAILABLE.into_response(),
+    ///     Err(_) => StatusCode::UNAUTHORIZED.into_response(),
+    /// }
+    /// ```
+    ///
+    #[cfg(feature = "one-time-token")]
+    pub async fn login_with_token(
+        &self,
+        tokens: &OneTimeTokens<Type, Pool>,
+        token: &str,
+    ) -> Result<Type, TokenError> {
+        let id = tokens
+            .consume(
+                token,
+                OneTimeTokens::<Type, Pool>::LOGIN,
+                self.pool.as_ref(),
+            )
+            .await?;
+
+        self.login_user_with_hooks(id.clone(), "token").await;
+        Ok(id)
+    }
+
+    #[cfg(feature = "oidc")]
+    fn oidc_flow_key(&self) -> String {
+        format!("{}_oidc_flow", self.config.session_id)
+    }
+
+    /// Starts an OpenID Connect login and returns the providers url to redirect the user to.
+    ///
+    /// The state, nonce and PKCE verifier are kept in the session until oidc_callback.
+    ///
+    /// # Examples
+    /// ```rust no_run ignore
+    /// match auth.oidc_login_url(&oidc).await {
+    ///     Ok(url) => Redirect::to(&url).into_response(),
+    ///     Err(_) => StatusCode::SERVICE_UNAVAILABLE.into_response(),
+    /// }
+    /// ```
+    ///
+    #[cfg(feature = "oidc")]
+    pub async fn oidc_login_url(
+        &self,
+        client: &OidcClient<Type, Pool>,
+    ) -> Result<String, OidcError> {
+        let (url, flow) = client.begin().await?;
+
+        self.session.set(&self.oidc_flow_key(), flow);
+        Ok(url)
+    }
+
+    /// Finishes an OpenID Connect login from the providers redirect to the callback.
+    ///
+    /// Checks the state, exchanges the code, verifies the ID token and its nonce, maps the
+    /// subject with the clients OidcSubjectMapper and logs the user in with
+    /// login_user_with_hooks.
+    /// The pending login is removed from the session whatever the outcome.
+    ///
+    /// # Examples
+    /// ```rust no_run ignore
+    /// match auth.oidc_callback(&oidc, &callback).await {
+    ///     Ok(_) => Redirect::to("/").into_response(),
+    ///     Err(OidcError::Http(_)) => StatusCode::SERVICE_UNAVAILABLE.into_response(),
+    ///     Err(err) => (StatusCode::UNAUTHORIZED, err.to_string()).into_response(),
+    /// }
+    /// ```
+    ///
+    #[cfg(feature = "oidc")]
+    pub async fn oidc_callback(
+        &self,
+        client: &OidcClient<Type, Pool>,
+        callback: &OidcCallback,
+    ) -> Result<Type, OidcError> {
+        let key = self.oidc_flow_key();
+        let flow = self
+            .session
+            .get_remove::<OidcFlow>(&key)
+            .ok_or(OidcError::NoPendingLogin)?;
+
+        let claims = client.finish(flow, callback).await?;
+        let id = client.map_subject(&claims, self.pool.as_ref()).await?;
+
+        self.login_user_with_hooks(id.clone(), "oidc").await;
+        Ok(id)
     }
 
     /// Tells the system to clear the user so they get reloaded upon next Axum request.
     ///
+    /// This is also published to the layer's invalidation bus if one is set.
+    ///
     /// # Examples
     /// ```rust no_run ignore
     ///  auth.cache_clear_user(user.id);
     /// ```
     ///
     pub fn cache_clear_user(&self, id: Type) {
-        let _ = self.cache.inner.remove(&id);
+        self.audit_invalidation(AuditKind::UserInvalidated {
+            target: serde_json::to_value(&id).unwrap_or_default(),
+        });
+        self.cache.clear_user(id);
     }
 
     /// Emptys the cache to force reload of all users.
     ///
+    /// This is also published to the layer's invalidation bus if one is set.
+    ///
     /// # Examples
     /// ```rust no_run ignore
     ///  auth.cache_clear_all();
     /// ```
     ///
     pub fn cache_clear_all(&self) {
-        self.cache.inner.clear();
+        self.audit_invalidation(AuditKind::AllUsersInvalidated);
+        self.cache.clear_all();
+    }
+
+    /// Tells the system to clear the users permission set so it gets reloaded on next use.
+    ///
+    /// This is also published to the layer's invalidation bus if one is set.
+    ///
+    /// # Examples
+    /// ```rust no_run ignore
+    ///  auth.cache_clear_permissions(user.id);
+    /// ```
+    ///
+    pub fn cache_clear_permissions(&self, id: Type) {
+        self.audit_invalidation(AuditKind::PermissionsInvalidated {
+            target: serde_json::to_value(&id).unwrap_or_default(),
+        });
+        self.cache.clear_permissions(id);
+    }
+
+    /// Emptys the permission cache to force reload of all permission sets.
+    ///
+    /// This is also published to the layer's invalidation bus if one is set.
+    ///
+    /// # Examples
+    /// ```rust no_run ignore
+    ///  auth.cache_clear_all_permissions();
+    /// ```
+    ///
+    pub fn cache_clear_all_permissions(&self) {
+        self.audit_invalidation(AuditKind::AllPermissionsInvalidated);
+        self.cache.clear_all_permissions();
+    }
+
+    /// Records an AuditEvent for the user id to the layer's audit sink, if one is set.
+    fn audit(&self, kind: AuditKind, id: &Type) {
+        if let Some(sink) = &self.audit {
+            sink.record(&AuditEvent::new(kind, id, &self.request));
+        }
+    }
+
+    /// Records a cache invalidation done by the current user.
+    fn audit_invalidation(&self, kind: AuditKind) {
+        self.audit(kind, &self.id);
+    }
+
+    /// Returns the user cache shared with the AuthSessionLayer.
+    ///
+    /// # Examples
+    /// ```rust no_run ignore
+    ///  let stats = auth.cache().stats();
+    /// ```
+    ///
+    pub fn cache(&self) -> &AuthCache<User, Type, Pool> {
+        &self.cache
     }
 
     /// Removes the user id from the Session preventing the system from auto login unless guest id is set.
     ///
+    /// The on_logout hook is not run, use logout_user_with_hooks for that.
+    ///
     /// # Examples
     /// ```rust no_run ignore
     ///  auth.logout_user();
@@ -171,7 +971,29 @@ where
     ///
     pub fn logout_user(&self) {
         self.session.remove(&self.config.session_id);
+        self.session.remove(&self.login_info_key());
+        #[cfg(feature = "totp")]
+        self.session.remove(&self.pending_key());
         self.session.renew();
+        telemetry::logout();
+        self.audit(AuditKind::Logout, &self.id);
+    }
+
+    /// Logs the user out like logout_user, then awaits the on_logout hook.
+    ///
+    /// # Examples
+    /// ```rust no_run ignore
+    ///  auth.logout_user_with_hooks().await;
+    /// ```
+    ///
+    pub async fn logout_user_with_hooks(&self) {
+        self.logout_user();
+
+        if let Some(hooks) = &self.hooks {
+            hooks
+                .on_logout(&self.id, &self.request, &self.session)
+                .await;
+        }
     }
 
     /// Used to check if a long living AuthSession is still logged in,
@@ -201,8 +1023,40 @@ where
         }
     }
 
+    /// Works like is_logged_in, but also checks the current user's version
+    /// against current_version, returning StaleUser if it changed.
+    ///
+    /// # Examples
+    /// ```rust no_run ignore
+    ///  auth.is_logged_in_current().await;
+    /// ```
+    ///
+    #[cfg(feature = "advanced")]
+    pub async fn is_logged_in_current(&mut self) -> AuthStatus {
+        match self.is_logged_in() {
+            AuthStatus::LoggedIn => match &self.current_user {
+                Some(user)
+                    if !AuthCache::<User, Type, Pool>::is_current(
+                        &self.id,
+                        user,
+                        self.pool.as_ref(),
+                    )
+                    .await =>
+                {
+                    AuthStatus::StaleUser
+                }
+                _ => AuthStatus::LoggedIn,
+            },
+            status => status,
+        }
+    }
+
     /// Reloads the user data into current user and cache.
     ///
+    /// The reloaded user is also returned by user() on every clone of this AuthSession.
+    /// If load_user fails with a transient error the current user is kept
+    /// and the error is set as load_error.
+    ///
     /// # Examples
     /// ```rust no_run ignore
     ///  auth.reload_user().await;
@@ -210,28 +1064,21 @@ where
     ///
     #[cfg(feature = "advanced")]
     pub async fn reload_user(&mut self) {
-        let current_user = User::load_user(self.id.clone(), self.pool.as_ref())
-            .await
-            .ok();
-
-        if self.config.cache {
-            let user = if let Some((_id, mut user)) = self.cache.inner.remove(&self.id) {
-                user.expires = Utc::now() + self.config.max_age;
-                user.current_user = current_user.clone();
-                user
-            } else {
-                AuthUser::<User, Type, Pool> {
-                    current_user: current_user.clone(),
-                    expires: Utc::now() + self.config.max_age,
-                    phantom_pool: Default::default(),
-                    phantom_type: Default::default(),
-                }
-            };
+        let _loading = self.loading.lock().await;
 
-            self.cache.inner.insert(self.id.clone(), user);
+        match self
+            .cache
+            .load_user(&self.id, self.pool.as_ref(), &self.config)
+            .await
+        {
+            Ok(current_user) => {
+                *self.lazy_user.write().unwrap_or_else(|e| e.into_inner()) =
+                    Some(Ok(current_user.clone()));
+                self.current_user = current_user;
+                self.load_error = None;
+            }
+            Err(err) => self.load_error = Some(err),
         }
-
-        self.current_user = current_user;
     }
 
     /// Updates the users expiration time so a request will not
@@ -271,6 +1118,133 @@ where
     }
 }
 
+impl<User, Type, Sess, Pool> AuthSession<User, Type, Sess, Pool>
+where
+    User: Authentication<User, Type, Pool> + Clone + Send + Sync + 'static,
+    Pool: Clone + Send + Sync + fmt::Debug + 'static,
+    Type: Eq
+        + Default
+        + Clone
+        + Send
+        + Sync
+        + Hash
+        + Serialize
+        + DeserializeOwned
+        + fmt::Display
+        + 'static,
+    Sess: DatabasePool + Clone + fmt::Debug + Sync + Send + 'static,
+{
+    /// Returns the current user, loading them on first call when lazy loading is enabled.
+    ///
+    /// The loaded user is shared with all clones of this AuthSession, and replaced by
+    /// reload_user. Returns the load error if load_user failed with a transient error.
+    ///
+    /// # Examples
+    /// ```rust no_run ignore
+    ///  let current_user = auth.user().await?.unwrap_or_default();
+    /// ```
+    ///
+    pub async fn user(&self) -> Result<Option<User>, Arc<Error>> {
+        if let Some(result) = self.loaded() {
+            return result;
+        }
+
+        let _loading = self.loading.lock().await;
+
+        // Another clone may have loaded the user while this one waited.
+        if let Some(result) = self.loaded() {
+            return result;
+        }
+
+        let span = tracing::info_span!(
+            "auth_session.load_user",
+            otel.kind = "internal",
+            user.id = %self.id,
+            auth.cache_hit = field::Empty,
+            auth.load_ms = field::Empty,
+        );
+
+        let result = self
+            .cache
+            .get_or_load_user(&self.id, &self.pool, &self.config)
+            .instrument(span)
+            .await;
+
+        *self.lazy_user.write().unwrap_or_else(|e| e.into_inner()) = Some(result.clone());
+        self.span.record(
+            "auth.authenticated",
+            matches!(&result, Ok(Some(user)) if user.is_authenticated()),
+        );
+        self.run_load_hooks(&result).await;
+        result
+    }
+
+    /// Calls the on_user_loaded or on_load_failed hook for the result of loading the user.
+    pub(crate) async fn run_load_hooks(&self, result: &Result<Option<User>, Arc<Error>>) {
+        let Some(hooks) = &self.hooks else {
+            return;
+        };
+
+        match result {
+            Ok(Some(_)) => {
+                hooks
+                    .on_user_loaded(&self.id, &self.request, &self.session)
+                    .await
+            }
+            Ok(None) => {}
+            Err(err) => {
+                hooks
+                    .on_load_failed(&self.id, err, &self.request, &self.session)
+                    .await
+            }
+        }
+    }
+
+    /// Checks the guard like Auth::check_session.
+    ///
+    /// # Examples
+    /// ```rust no_run ignore
+    /// if let Err(reason) = auth
+    ///     .authorize(
+    ///         Auth::<User, i64, Pool>::build([Method::GET], true)
+    ///             .requires(Rights::permission("admin:view")),
+    ///         &method,
+    ///     )
+    ///     .await
+    /// {
+    ///     return (StatusCode::FORBIDDEN, reason.to_string()).into_response();
+    /// }
+    /// ```
+    ///
+    pub async fn authorize(
+        &self,
+        guard: &Auth<User, Type, Pool>,
+        method: &Method,
+    ) -> Result<(), DenyReason>
+    where
+        User: HasPermission<Pool> + Default,
+    {
+        guard.check_session(self, method).await
+    }
+
+    /// Writes a denial to the audit sink and passes it to the on_access_denied hook.
+    pub(crate) async fn record_denial(&self, policy: &str, reason: DenyReason) {
+        self.audit(
+            AuditKind::AccessDenied {
+                policy: policy.to_string(),
+                reason,
+            },
+            &self.id,
+        );
+
+        if let Some(hooks) = &self.hooks {
+            hooks
+                .on_access_denied(&self.id, policy, reason, &self.request, &self.session)
+                .await;
+        }
+    }
+}
+
 /// Used to display how the users Auth data is compared to what
 /// a AuthSessions Data was set as. To ensure nothing changed.
 ///
@@ -289,6 +1263,312 @@ pub enum AuthStatus {
     /// the user is logged out.
     LoggedOut,
     /// The user account was removed from cache
-    /// so it needs reloading.
+    /// or its version changed so it needs reloading.
     StaleUser,
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        testing::{loads, Client, TestLayer, User, DOWN, MISSING},
+        AuthHooks, MemorySink, Rights,
+    };
+    use axum_session::SessionNullPool;
+
+    /// Hooks writing every call to a list.
+    #[derive(Debug, Default)]
+    struct Recorder(std::sync::Mutex<Vec<String>>);
+
+    impl Recorder {
+        fn push(&self, event: String) {
+            self.0.lock().unwrap().push(event);
+        }
+
+        fn take(&self) -> Vec<String> {
+            std::mem::take(&mut self.0.lock().unwrap())
+        }
+    }
+
+    #[async_trait]
+    impl AuthHooks<i64, SessionNullPool> for Recorder {
+        async fn on_login(&self, id: &i64, _: &RequestInfo, _: &Session<SessionNullPool>) {
+            self.push(format!("login {id}"));
+        }
+
+        async fn on_logout(&self, id: &i64, _: &RequestInfo, _: &Session<SessionNullPool>) {
+            self.push(format!("logout {id}"));
+        }
+
+        async fn on_user_loaded(&self, id: &i64, _: &RequestInfo, _: &Session<SessionNullPool>) {
+            self.push(format!("loaded {id}"));
+        }
+
+        async fn on_load_failed(
+            &self,
+            id: &i64,
+            _: &Error,
+            _: &RequestInfo,
+            _: &Session<SessionNullPool>,
+        ) {
+            self.push(format!("failed {id}"));
+        }
+
+        async fn on_access_denied(
+            &self,
+            id: &i64,
+            policy: &str,
+            reason: DenyReason,
+            _: &RequestInfo,
+            _: &Session<SessionNullPool>,
+        ) {
+            self.push(format!("denied {id} {policy} {reason}"));
+        }
+    }
+
+    #[tokio::test]
+    async fn missing_users_are_cached_and_transient_errors_are_not() {
+        let client = Client::new(TestLayer::new(None)).await;
+        let missing = MISSING.start;
+
+        client
+            .get(move |auth| async move { auth.login_user(missing) })
+            .await;
+
+        for _ in 0..2 {
+            let (_, found) = client
+                .get(|auth| async move { (auth.current_user.is_some(), auth.load_error.is_some()) })
+                .await;
+            assert_eq!(found, Some((false, false)));
+        }
+
+        assert_eq!(loads(missing), 1);
+
+        let client = Client::new(TestLayer::new(None)).await;
+        let down = DOWN.start;
+
+        client
+            .get(move |auth| async move { auth.login_user(down) })
+            .await;
+
+        for _ in 0..2 {
+            let (_, found) = client
+                .get(|auth| async move { (auth.current_user.is_some(), auth.load_error.is_some()) })
+                .await;
+            assert_eq!(found, Some((false, true)));
+        }
+
+        assert_eq!(loads(down), 2);
+    }
+
+    #[tokio::test]
+    async fn lazy_users_are_loaded_once_and_shared_between_clones() {
+        let client = Client::new(
+            TestLayer::new(None)
+                .with_config(AuthConfig::default().with_lazy_load(true).set_cache(false)),
+        )
+        .await;
+
+        client.get(|auth| async move { auth.login_user(10) }).await;
+        assert_eq!(loads(10), 0);
+
+        let (_, loaded) = client
+            .get(|auth| async move {
+                let clone = auth.clone();
+                assert!(auth.current_user.is_none());
+
+                let (user, cloned) = tokio::join!(auth.user(), clone.user());
+                let shared = auth.user().await.unwrap().unwrap().id;
+                (
+                    user.unwrap().unwrap().id,
+                    cloned.unwrap().unwrap().id,
+                    shared,
+                )
+            })
+            .await;
+
+        assert_eq!(loaded, Some((10, 10, 10)));
+        assert_eq!(loads(10), 1);
+    }
+
+    #[tokio::test]
+    async fn only_the_with_hooks_variants_run_login_and_logout_hooks() {
+        let hooks = Arc::new(Recorder::default());
+        let client = Client::new(TestLayer::new(None).with_hooks(hooks.clone())).await;
+
+        client.get(|auth| async move { auth.login_user(80) }).await;
+        client.get(|auth| async move { auth.logout_user() }).await;
+        assert_eq!(hooks.take(), ["loaded 80"]);
+
+        client
+            .get(|auth| async move { auth.login_user_with_hooks(80, "password").await })
+            .await;
+        client.get(|_| async {}).await;
+        client
+            .get(|auth| async move { auth.logout_user_with_hooks().await })
+            .await;
+        assert_eq!(
+            hooks.take(),
+            ["login 80", "loaded 80", "loaded 80", "logout 80"]
+        );
+
+        let down = DOWN.start + 80;
+        client
+            .get(move |auth| async move { auth.login_user_with_hooks(down, "password").await })
+            .await;
+        client.get(|_| async {}).await;
+        assert_eq!(
+            hooks.take(),
+            [format!("login {down}"), format!("failed {down}")]
+        );
+    }
+
+    #[tokio::test]
+    async fn logins_logouts_denials_and_clears_are_audited_once() {
+        let sink = Arc::new(MemorySink::default());
+        let hooks = Arc::new(Recorder::default());
+        let client = Client::new(
+            TestLayer::new(None)
+                .with_config(AuthConfig::default().with_client_ip_header("x-forwarded-for"))
+                .with_audit_sink(sink.clone())
+                .with_hooks(hooks.clone()),
+        )
+        .await;
+
+        client
+            .send(
+                http::Request::post("/login").header("x-forwarded-for", "6.6.6.6, 203.0.113.9"),
+                |auth| async move { auth.login_user(81) },
+            )
+            .await;
+
+        let (_, denied) = client
+            .get(|auth| async move {
+                let mut guard = Auth::<User, i64, ()>::build([Method::GET], true);
+                guard
+                    .named("admin_only")
+                    .requires(Rights::permission("admin"));
+
+                (
+                    auth.authorize(&guard, &Method::GET).await,
+                    guard.validate_session(&auth, &Method::GET).await,
+                )
+            })
+            .await;
+        assert_eq!(denied, Some((Err(DenyReason::Rights), false)));
+
+        client
+            .get(|auth| async move {
+                auth.cache_clear_user(81);
+                auth.logout_user();
+            })
+            .await;
+
+        let events = sink.events();
+        let kinds: Vec<_> = events.iter().map(|event| event.kind.clone()).collect();
+        let denial = AuditKind::AccessDenied {
+            policy: "admin_only".to_string(),
+            reason: DenyReason::Rights,
+        };
+
+        assert_eq!(
+            kinds,
+            [
+                AuditKind::Login,
+                denial.clone(),
+                denial,
+                AuditKind::UserInvalidated { target: 81.into() },
+                AuditKind::Logout,
+            ]
+        );
+        assert!(events.iter().all(|event| event.user_id == 81));
+        assert_eq!(events[0].client_ip, Some("203.0.113.9".parse().unwrap()));
+        assert_eq!(events[0].path, "/login");
+        let denials: Vec<_> = hooks
+            .take()
+            .into_iter()
+            .filter(|event| event.starts_with("denied"))
+            .collect();
+        assert_eq!(denials, ["denied 81 admin_only rights"; 2]);
+    }
+
+    /// A single user alice with id 90, whose hash was made with older parameters.
+    #[cfg(feature = "credentials")]
+    struct Users {
+        hash: std::sync::Mutex<String>,
+        updates: std::sync::Mutex<usize>,
+    }
+
+    #[cfg(feature = "credentials")]
+    #[async_trait]
+    impl CredentialStore<i64, ()> for Users {
+        async fn lookup(
+            &self,
+            username: &str,
+            _pool: Option<&()>,
+        ) -> Result<Option<crate::Credentials<i64>>, Error> {
+            Ok((username == "alice").then(|| crate::Credentials {
+                id: 90,
+                hash: self.hash.lock().unwrap().clone(),
+            }))
+        }
+
+        async fn update_hash(
+            &self,
+            id: &i64,
+            hash: String,
+            _pool: Option<&()>,
+        ) -> Result<(), Error> {
+            assert_eq!(*id, 90);
+            *self.hash.lock().unwrap() = hash;
+            *self.updates.lock().unwrap() += 1;
+            Ok(())
+        }
+    }
+
+    #[cfg(feature = "credentials")]
+    #[tokio::test]
+    async fn login_with_password_checks_the_password_and_rehashes_old_hashes() {
+        let hashing = crate::PasswordHashing::new(8, 1, 1).unwrap();
+        let users = Arc::new(Users {
+            hash: std::sync::Mutex::new(
+                crate::PasswordHashing::new(16, 1, 1)
+                    .unwrap()
+                    .hash("hunter2")
+                    .unwrap(),
+            ),
+            updates: std::sync::Mutex::new(0),
+        });
+        let client = Client::new(
+            TestLayer::new(None)
+                .with_config(AuthConfig::default().with_password_hashing(hashing.clone())),
+        )
+        .await;
+
+        for (username, password) in [("alice", "hunter3"), ("bob", "hunter2")] {
+            let store = users.clone();
+            let (_, result) = client
+                .get(move |auth| async move {
+                    auth.login_with_password(&*store, username, password).await
+                })
+                .await;
+            assert!(matches!(result, Some(Err(LoginError::InvalidCredentials))));
+        }
+
+        let store = users.clone();
+        let (_, result) =
+            client
+                .get(move |auth| async move {
+                    auth.login_with_password(&*store, "alice", "hunter2").await
+                })
+                .await;
+        assert!(matches!(result, Some(Ok(90))));
+
+        let (_, user) = client
+            .get(|auth| async move { auth.current_user.map(|user| user.id) })
+            .await;
+        assert_eq!(user, Some(Some(90)));
+        assert_eq!(*users.updates.lock().unwrap(), 1);
+        assert!(!hashing.needs_rehash(&users.hash.lock().unwrap()));
+    }
+}
@@ -0,0 +1,176 @@
+use crate::{AuthCache, AuthConfig, AuthUser, Authentication};
+use anyhow::Error;
+use chrono::{DateTime, Utc};
+use serde::{de::DeserializeOwned, Deserialize, Serialize};
+use std::{fmt, hash::Hash, path::Path};
+use tokio::io::AsyncWriteExt;
+
+/// A single cached user as stored in a snapshot file.
+#[derive(Serialize, Deserialize)]
+struct SnapshotEntry<Type, User> {
+    id: Type,
+    expires: DateTime<Utc>,
+    /// Missing in snapshots of older versions, which are treated as loaded on restore.
+    #[serde(default)]
+    loaded_at: Option<DateTime<Utc>>,
+    user: Option<User>,
+}
+
+impl<User, Type, Pool> AuthCache<User, Type, Pool>
+where
+    User: Authentication<User, Type, Pool> + Clone + Send + Serialize + DeserializeOwned,
+    Pool: Clone + Send + Sync + fmt::Debug + 'static,
+    Type: Eq + Default + Clone + Send + Sync + Hash + Serialize + DeserializeOwned + 'static,
+{
+    /// Writes all unexpired cached users to a JSON file, returning how many were written.
+    ///
+    /// Meant to be called on graceful shutdown so the cache can be restored on startup.
+    /// The snapshot is written to a temporary file next to path, synced and then renamed
+    /// over path, so a crash never leaves a partial snapshot. On unix the file is only
+    /// readable by its owner, elsewhere it gets the default permissions of new files.
+    ///
+    /// # Examples
+    /// ```rust no_run ignore
+    ///  cache.save_snapshot("user_cache.json").await?;
+    /// ```
+    ///
+    pub async fn save_snapshot(&self, path: impl AsRef<Path>) -> Result<usize, Error> {
+        let now = Utc::now();
+        let entries: Vec<_> = self
+            .inner
+            .iter()
+            .filter(|entry| entry.expires > now)
+            .map(|entry| SnapshotEntry {
+                id: entry.key().clone(),
+                expires: entry.expires,
+                loaded_at: Some(entry.loaded_at()),
+                user: entry.current_user.clone(),
+            })
+            .collect();
+
+        let path = path.as_ref();
+        let mut tmp = path.as_os_str().to_owned();
+        tmp.push(".tmp");
+
+        // A leftover temporary file could have wider permissions than a new one gets.
+        let _ = tokio::fs::remove_file(&tmp).await;
+
+        let mut options = tokio::fs::OpenOptions::new();
+        options.write(true).create_new(true);
+        #[cfg(unix)]
+        options.mode(0o600);
+
+        let mut file = options.open(&tmp).await?;
+        file.write_all(&serde_json::to_vec(&entries)?).await?;
+        file.sync_all().await?;
+        drop(file);
+
+        tokio::fs::rename(&tmp, path).await?;
+        Ok(entries.len())
+    }
+
+    /// Restores cached users from a file written by save_snapshot, returning how many were restored.
+    ///
+    /// Users that expired since the snapshot was taken and users already in the cache are skipped.
+    /// A missing file or a config with caching disabled restores nothing. Users keep the time
+    /// they were loaded at, so refresh_ahead reloads them as if the app never restarted.
+    ///
+    /// # Examples
+    /// ```rust no_run ignore
+    ///  cache.load_snapshot("user_cache.json", &config).await?;
+    /// ```
+    ///
+    pub async fn load_snapshot(
+        &self,
+        path: impl AsRef<Path>,
+        config: &AuthConfig<Type>,
+    ) -> Result<usize, Error> {
+        if !config.cache {
+            return Ok(0);
+        }
+
+        let data = match tokio::fs::read(path).await {
+            Ok(data) => data,
+            Err(err) if err.kind() == std::io::ErrorKind::NotFound => return Ok(0),
+            Err(err) => return Err(err.into()),
+        };
+        let entries: Vec<SnapshotEntry<Type, User>> = serde_json::from_slice(&data)?;
+        let now = Utc::now();
+        let mut restored = 0;
+
+        for entry in entries.into_iter().filter(|entry| entry.expires > now) {
+            if !self.inner.contains_key(&entry.id) {
+                let user = AuthUser::<User, Type, Pool> {
+                    current_user: entry.user,
+                    expires: entry.expires,
+                    loaded_at: entry.loaded_at.unwrap_or(now),
+                    phantom_pool: Default::default(),
+                    phantom_type: Default::default(),
+                };
+
+                self.inner.insert(entry.id, user);
+                restored += 1;
+            }
+        }
+
+        tracing::info!("restored {} users into the user cache.", restored);
+        Ok(restored)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use async_trait::async_trait;
+
+    #[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+    struct User {
+        id: i64,
+    }
+
+    #[async_trait]
+    impl Authentication<User, i64, ()> for User {
+        async fn load_user(userid: i64, _pool: Option<&()>) -> Result<User, Error> {
+            Ok(User { id: userid })
+        }
+
+        fn is_authenticated(&self) -> bool {
+            true
+        }
+
+        fn is_active(&self) -> bool {
+            true
+        }
+
+        fn is_anonymous(&self) -> bool {
+            false
+        }
+    }
+
+    #[tokio::test]
+    async fn snapshots_keep_loaded_at_and_respect_the_cache_setting() {
+        let path = std::env::temp_dir().join(format!("auth_snapshot_{}.json", std::process::id()));
+        let saved = AuthCache::<User, i64, ()>::new(Utc::now());
+        let loaded_at = Utc::now() - chrono::Duration::try_minutes(50).unwrap();
+
+        saved.insert(
+            1,
+            Some(User { id: 1 }),
+            Utc::now() + chrono::Duration::try_hours(1).unwrap(),
+        );
+        saved.inner.get_mut(&1).unwrap().loaded_at = loaded_at;
+        assert_eq!(saved.save_snapshot(&path).await.unwrap(), 1);
+
+        let disabled = AuthCache::<User, i64, ()>::new(Utc::now());
+        let config = AuthConfig::<i64>::default().set_cache(false);
+        assert_eq!(disabled.load_snapshot(&path, &config).await.unwrap(), 0);
+        assert!(disabled.inner.is_empty());
+
+        let restored = AuthCache::<User, i64, ()>::new(Utc::now());
+        let config = AuthConfig::<i64>::default();
+        assert_eq!(restored.load_snapshot(&path, &config).await.unwrap(), 1);
+        assert_eq!(restored.inner.get(&1).unwrap().loaded_at(), loaded_at);
+
+        let _ = std::fs::remove_file(&path);
+    }
+}
@@ -0,0 +1,64 @@
+//! Metrics recorded through the `metrics` facade when the `metrics` feature is enabled.
+//!
+//! Without the feature every function here is a no-op.
+#[cfg(feature = "metrics")]
+use metrics::{counter, histogram};
+use std::time::Duration;
+
+/// Records a finished load_user call. outcome is `ok`, `not_found` or `error`.
+#[allow(unused_variables)]
+pub(crate) fn load_user(elapsed: Duration, outcome: &'static str) {
+    #[cfg(feature = "metrics")]
+    {
+        histogram!("axum_session_auth_load_user_seconds", "outcome" => outcome)
+            .record(elapsed.as_secs_f64());
+        counter!("axum_session_auth_load_user_total", "outcome" => outcome).increment(1);
+    }
+}
+
+/// Records a user cache lookup.
+#[allow(unused_variables)]
+pub(crate) fn cache_lookup(hit: bool) {
+    #[cfg(feature = "metrics")]
+    {
+        let result = if hit { "hit" } else { "miss" };
+
+        counter!("axum_session_auth_cache_lookups_total", "result" => result).increment(1);
+    }
+}
+
+/// Records users removed from the cache by an expiry sweep.
+#[allow(unused_variables)]
+pub(crate) fn cache_evictions(evicted: u64) {
+    #[cfg(feature = "metrics")]
+    counter!("axum_session_auth_cache_evictions_total").increment(evicted);
+}
+
+/// Records a guard decision. reason is `allowed` for allowed requests.
+#[allow(unused_variables)]
+pub(crate) fn guard(policy: &str, allowed: bool, reason: &'static str) {
+    #[cfg(feature = "metrics")]
+    {
+        let decision = if allowed { "allow" } else { "deny" };
+
+        counter!(
+            "axum_session_auth_guard_decisions_total",
+            "policy" => policy.to_owned(),
+            "decision" => decision,
+            "reason" => reason
+        )
+        .increment(1);
+    }
+}
+
+/// Records a call to login_user.
+pub(crate) fn login() {
+    #[cfg(feature = "metrics")]
+    counter!("axum_session_auth_logins_total").increment(1);
+}
+
+/// Records a call to logout_user.
+pub(crate) fn logout() {
+    #[cfg(feature = "metrics")]
+    counter!("axum_session_auth_logouts_total").increment(1);
+}
@@ -0,0 +1,257 @@
+//! Test user and request helpers shared by the unit tests of the session, service and
+//! authenticator modules.
+
+use crate::{AuthSession, AuthSessionLayer, Authentication, HasPermission, UserNotFound};
+use anyhow::Error;
+use async_trait::async_trait;
+use axum_core::body::Body;
+use axum_session::{SessionConfig, SessionLayer, SessionNullPool, SessionStore};
+use http::{header, HeaderValue, Request, Response};
+use serde::{Deserialize, Serialize};
+use std::{
+    collections::{HashMap, HashSet},
+    convert::Infallible,
+    future::Future,
+    sync::{Arc, Mutex},
+};
+use tower::{Layer, ServiceExt};
+
+/// The anonymous guest user.
+pub(crate) const GUEST: i64 = 1;
+/// A user with the admin permission.
+pub(crate) const ADMIN: i64 = 2;
+/// Ids load_user fails for with UserNotFound.
+pub(crate) const MISSING: std::ops::Range<i64> = 400..500;
+/// Ids load_user fails for with a transient error.
+pub(crate) const DOWN: std::ops::Range<i64> = 500..600;
+
+static LOADS: Mutex<Option<HashMap<i64, usize>>> = Mutex::new(None);
+static VERSIONS: Mutex<Option<HashMap<i64, i64>>> = Mutex::new(None);
+
+/// Returns how often load_user was called for the id.
+///
+/// Tests counting loads use their own ids, as the counts are shared by all tests.
+pub(crate) fn loads(id: i64) -> usize {
+    LOADS
+        .lock()
+        .unwrap()
+        .get_or_insert_with(HashMap::new)
+        .get(&id)
+        .copied()
+        .unwrap_or_default()
+}
+
+/// Sets the stored version current_version returns for the id.
+pub(crate) fn set_version(id: i64, version: i64) {
+    VERSIONS
+        .lock()
+        .unwrap()
+        .get_or_insert_with(HashMap::new)
+        .insert(id, version);
+}
+
+fn version(id: i64) -> Option<i64> {
+    VERSIONS
+        .lock()
+        .unwrap()
+        .get_or_insert_with(HashMap::new)
+        .get(&id)
+        .copied()
+}
+
+/// Every id other than GUEST, ADMIN, MISSING and DOWN is a user with the read permission.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub(crate) struct User {
+    pub(crate) id: i64,
+    pub(crate) anonymous: bool,
+    pub(crate) permissions: Vec<String>,
+    pub(crate) version: Option<i64>,
+}
+
+impl Default for User {
+    fn default() -> Self {
+        Self {
+            id: 0,
+            anonymous: true,
+            permissions: Vec::new(),
+            version: None,
+        }
+    }
+}
+
+#[async_trait]
+impl Authentication<User, i64, ()> for User {
+    async fn load_user(userid: i64, _pool: Option<&()>) -> Result<User, Error> {
+        *LOADS
+            .lock()
+            .unwrap()
+            .get_or_insert_with(HashMap::new)
+            .entry(userid)
+            .or_default() += 1;
+
+        let permissions: &[&str] = match userid {
+            _ if MISSING.contains(&userid) => return Err(UserNotFound.into()),
+            _ if DOWN.contains(&userid) => return Err(anyhow::anyhow!("database is down")),
+            GUEST => &["public"],
+            ADMIN => &["read", "admin"],
+            _ => &["read"],
+        };
+
+        Ok(User {
+            id: userid,
+            anonymous: userid == GUEST,
+            permissions: permissions.iter().map(|p| p.to_string()).collect(),
+            version: version(userid),
+        })
+    }
+
+    fn version(&self) -> Option<i64> {
+        self.version
+    }
+
+    async fn current_version(userid: i64, _pool: Option<&()>) -> Result<Option<i64>, Error> {
+        Ok(version(userid))
+    }
+
+    fn is_authenticated(&self) -> bool {
+        !self.anonymous
+    }
+
+    fn is_active(&self) -> bool {
+        true
+    }
+
+    fn is_anonymous(&self) -> bool {
+        self.anonymous
+    }
+}
+
+#[async_trait]
+impl HasPermission<()> for User {
+    async fn has(&self, perm: &str, _pool: &Option<&()>) -> bool {
+        self.permissions.iter().any(|p| p == perm)
+    }
+
+    async fn load_permissions(&self, _pool: &Option<&()>) -> Option<HashSet<String>> {
+        Some(self.permissions.iter().cloned().collect())
+    }
+}
+
+pub(crate) type TestLayer = AuthSessionLayer<User, i64, SessionNullPool, ()>;
+pub(crate) type TestSession = AuthSession<User, i64, SessionNullPool, ()>;
+
+/// Sends requests through a SessionLayer and the AuthSessionLayer, keeping the session
+/// cookies, or the session header in rest_mode, between requests like a browser.
+pub(crate) struct Client {
+    sessions: SessionLayer<SessionNullPool>,
+    auth: TestLayer,
+    cookies: Mutex<HashMap<String, String>>,
+    #[cfg(feature = "rest_mode")]
+    session: Mutex<Option<HeaderValue>>,
+}
+
+impl Client {
+    pub(crate) async fn new(auth: TestLayer) -> Self {
+        let store = SessionStore::new(None, SessionConfig::default())
+            .await
+            .unwrap();
+
+        Self {
+            sessions: SessionLayer::new(store),
+            auth,
+            cookies: Mutex::default(),
+            #[cfg(feature = "rest_mode")]
+            session: Mutex::default(),
+        }
+    }
+
+    /// Sends a GET request to / and runs the handler with its AuthSession.
+    pub(crate) async fn get<F, Fut, R>(&self, handler: F) -> (Response<Body>, Option<R>)
+    where
+        F: FnOnce(TestSession) -> Fut + Send + 'static,
+        Fut: Future<Output = R> + Send + 'static,
+        R: Send + 'static,
+    {
+        self.send(Request::get("/"), handler).await
+    }
+
+    /// Sends the request and runs the handler with its AuthSession.
+    ///
+    /// Returns the handlers result, or None if the request never reached it.
+    pub(crate) async fn send<F, Fut, R>(
+        &self,
+        request: http::request::Builder,
+        handler: F,
+    ) -> (Response<Body>, Option<R>)
+    where
+        F: FnOnce(TestSession) -> Fut + Send + 'static,
+        Fut: Future<Output = R> + Send + 'static,
+        R: Send + 'static,
+    {
+        let mut request = request.body(Body::empty()).unwrap();
+        let cookie = self
+            .cookies
+            .lock()
+            .unwrap()
+            .iter()
+            .map(|(name, value)| format!("{name}={value}"))
+            .collect::<Vec<_>>()
+            .join("; ");
+
+        if !cookie.is_empty() {
+            request
+                .headers_mut()
+                .insert(header::COOKIE, HeaderValue::from_str(&cookie).unwrap());
+        }
+
+        #[cfg(feature = "rest_mode")]
+        if let Some(session) = self.session.lock().unwrap().clone() {
+            request.headers_mut().insert("session", session);
+        }
+
+        let result = Arc::new(Mutex::new(None));
+        let handler = Arc::new(Mutex::new(Some(handler)));
+        let slot = result.clone();
+        let inner = tower::service_fn(move |request: Request<Body>| {
+            let handler = handler.lock().unwrap().take();
+            let slot = slot.clone();
+
+            async move {
+                if let (Some(handler), Some(auth)) =
+                    (handler, request.extensions().get::<TestSession>().cloned())
+                {
+                    let value = handler(auth).await;
+                    *slot.lock().unwrap() = Some(value);
+                }
+
+                Ok::<_, Infallible>(Response::new(Body::empty()))
+            }
+        });
+
+        let response = self
+            .sessions
+            .layer(self.auth.layer(inner))
+            .oneshot(request)
+            .await
+            .unwrap();
+
+        for value in response.headers().get_all(header::SET_COOKIE) {
+            let pair = value.to_str().unwrap().split(';').next().unwrap();
+
+            if let Some((name, value)) = pair.split_once('=') {
+                self.cookies
+                    .lock()
+                    .unwrap()
+                    .insert(name.trim().to_string(), value.trim().to_string());
+            }
+        }
+
+        #[cfg(feature = "rest_mode")]
+        if let Some(session) = response.headers().get("session") {
+            *self.session.lock().unwrap() = Some(session.clone());
+        }
+
+        let value = result.lock().unwrap().take();
+        (response, value)
+    }
+}
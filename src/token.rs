@@ -0,0 +1,364 @@
+use anyhow::Error;
+use async_trait::async_trait;
+use chrono::{DateTime, Duration, Utc};
+use dashmap::DashMap;
+use data_encoding::{BASE64URL_NOPAD, HEXLOWER};
+use rand::RngCore;
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use std::{fmt, sync::Arc};
+
+/// A stored one time token, saved under the hash_one_time_token hash of its purpose and token.
+///
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct OneTimeToken<Type> {
+    /// Id of the user the token was issued for.
+    pub id: Type,
+    /// What the token may be used for, like `login` or `verify_email`.
+    pub purpose: String,
+    pub expires_at: DateTime<Utc>,
+}
+
+/// Where one time tokens are kept by their hash_one_time_token hash.
+///
+/// # Examples
+/// ```rust no_run ignore
+/// #[async_trait]
+/// impl TokenStore<i64, PgPool> for Tokens {
+///     async fn save(&self, hash: &str, token: &OneTimeToken<i64>, pool: Option<&PgPool>) -> Result<(), anyhow::Error> {
+///         sqlx::query("INSERT INTO login_tokens (hash, user_id, purpose, expires_at) VALUES ($1, $2, $3, $4)")
+///             .bind(hash)
+///             .bind(token.id)
+///             .bind(&token.purpose)
+///             .bind(token.expires_at)
+///             .execute(pool.unwrap())
+///             .await?;
+///
+///         Ok(())
+///     }
+///
+///     async fn consume(&self, hash: &str, pool: Option<&PgPool>) -> Result<Option<OneTimeToken<i64>>, anyhow::Error> {
+///         let row: Option<(i64, String, DateTime<Utc>)> =
+///             sqlx::query_as("DELETE FROM login_tokens WHERE hash = $1 RETURNING user_id, purpose, expires_at")
+///                 .bind(hash)
+///                 .fetch_optional(pool.unwrap())
+///                 .await?;
+///
+///         Ok(row.map(|(id, purpose, expires_at)| OneTimeToken { id, purpose, expires_at }))
+///     }
+/// }
+/// ```
+///
+#[async_trait]
+pub trait TokenStore<Type, Pool>: Send + Sync
+where
+    Type: Send + Sync,
+    Pool: Send + Sync,
+{
+    /// Stores a new token.
+    async fn save(
+        &self,
+        hash: &str,
+        token: &OneTimeToken<Type>,
+        pool: Option<&Pool>,
+    ) -> Result<(), Error>;
+
+    /// Removes and returns the token with this hash, or None if there is none.
+    ///
+    /// Must remove the token in the same step it is read, so it can only be returned once
+    /// even when the same token is sent twice at the same time.
+    async fn consume(
+        &self,
+        hash: &str,
+        pool: Option<&Pool>,
+    ) -> Result<Option<OneTimeToken<Type>>, Error>;
+}
+
+/// TokenStore keeping tokens in memory, only shared within one process.
+///
+/// Expired tokens are removed whenever a new token is saved.
+///
+#[derive(Debug, Clone)]
+pub struct MemoryTokenStore<Type> {
+    inner: Arc<DashMap<String, OneTimeToken<Type>>>,
+}
+
+impl<Type> Default for MemoryTokenStore<Type> {
+    fn default() -> Self {
+        Self {
+            inner: Arc::new(DashMap::new()),
+        }
+    }
+}
+
+#[async_trait]
+impl<Type, Pool> TokenStore<Type, Pool> for MemoryTokenStore<Type>
+where
+    Type: Clone + Send + Sync,
+    Pool: Send + Sync,
+{
+    async fn save(
+        &self,
+        hash: &str,
+        token: &OneTimeToken<Type>,
+        _pool: Option<&Pool>,
+    ) -> Result<(), Error> {
+        let now = Utc::now();
+
+        self.inner.retain(|_, token| token.expires_at > now);
+        self.inner.insert(hash.to_owned(), token.clone());
+        Ok(())
+    }
+
+    async fn consume(
+        &self,
+        hash: &str,
+        _pool: Option<&Pool>,
+    ) -> Result<Option<OneTimeToken<Type>>, Error> {
+        Ok(self.inner.remove(hash).map(|(_, token)| token))
+    }
+}
+
+/// Why a one time token was not accepted.
+///
+#[derive(Debug)]
+pub enum TokenError {
+    /// The token is unknown, was already used or is for another purpose.
+    Invalid,
+    /// The token expired.
+    Expired,
+    /// The TokenStore failed.
+    Store(Error),
+}
+
+impl fmt::Display for TokenError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            TokenError::Invalid => f.write_str("invalid or already used token"),
+            TokenError::Expired => f.write_str("the token expired"),
+            TokenError::Store(err) => write!(f, "token store failed: {}", err),
+        }
+    }
+}
+
+impl std::error::Error for TokenError {}
+
+/// Issues and consumes single use tokens, like the links of a passwordless email login.
+///
+/// Only the SHA-256 hash of a tokens purpose and the token is stored, and consuming a
+/// token removes it so it can not be replayed. A token is only found under its own
+/// purpose, so trying it for another purpose does not use it up.
+///
+/// # Examples
+/// ```rust no_run ignore
+/// let tokens = OneTimeTokens::new(Arc::new(MemoryTokenStore::default()))
+///     .with_ttl(Duration::try_minutes(10).unwrap());
+///
+/// let token = tokens.issue_login(user.id, Some(&pool)).await?;
+/// send_email(&user.email, format!("https://app.example.com/login/{token}")).await?;
+/// ```
+///
+#[derive(Clone)]
+pub struct OneTimeTokens<Type, Pool> {
+    pub(crate) store: Arc<dyn TokenStore<Type, Pool>>,
+    pub(crate) ttl: Duration,
+}
+
+impl<Type, Pool> fmt::Debug for OneTimeTokens<Type, Pool> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("OneTimeTokens")
+            .field("ttl", &self.ttl)
+            .finish_non_exhaustive()
+    }
+}
+
+impl<Type, Pool> OneTimeTokens<Type, Pool>
+where
+    Type: Send + Sync,
+    Pool: Send + Sync,
+{
+    /// Purpose of the tokens AuthSession::login_with_token accepts.
+    pub const LOGIN: &'static str = "login";
+
+    /// Creates OneTimeTokens whose tokens expire after 15 minutes.
+    pub fn new(store: Arc<dyn TokenStore<Type, Pool>>) -> Self {
+        Self {
+            store,
+            ttl: Duration::try_minutes(15).unwrap_or_default(),
+        }
+    }
+
+    /// Set's how long issued tokens can be used. Defaults to 15 minutes.
+    ///
+    /// # Examples
+    /// ```rust
+    /// use axum_session_auth::{MemoryTokenStore, OneTimeTokens};
+    /// use chrono::Duration;
+    /// use std::sync::Arc;
+    ///
+    /// let tokens = OneTimeTokens::<i64, ()>::new(Arc::new(MemoryTokenStore::default()))
+    ///     .with_ttl(Duration::try_minutes(10).unwrap());
+    /// ```
+    ///
+    #[must_use]
+    pub fn with_ttl(mut self, ttl: Duration) -> Self {
+        self.ttl = ttl;
+        self
+    }
+
+    /// Creates and stores a token for the user and purpose. Give it to the user once.
+    pub async fn issue(
+        &self,
+        id: Type,
+        purpose: impl Into<String>,
+        pool: Option<&Pool>,
+    ) -> Result<String, Error> {
+        let token = generate_one_time_token();
+        let stored = OneTimeToken {
+            id,
+            purpose: purpose.into(),
+            expires_at: Utc::now() + self.ttl,
+        };
+
+        self.store
+            .save(&hash_one_time_token(&stored.purpose, &token), &stored, pool)
+            .await?;
+
+        Ok(token)
+    }
+
+    /// Creates and stores a token AuthSession::login_with_token logs the user in with.
+    pub async fn issue_login(&self, id: Type, pool: Option<&Pool>) -> Result<String, Error> {
+        self.issue(id, Self::LOGIN, pool).await
+    }
+
+    /// Uses up a token and returns the id of its user if it is for this purpose and not expired.
+    ///
+    /// # Examples
+    /// ```rust no_run ignore
+    /// let id = tokens.consume(&token, "verify_email", Some(&pool)).await?;
+    /// ```
+    ///
+    pub async fn consume(
+        &self,
+        token: &str,
+        purpose: &str,
+        pool: Option<&Pool>,
+    ) -> Result<Type, TokenError> {
+        let stored = self
+            .store
+            .consume(&hash_one_time_token(purpose, token.trim()), pool)
+            .await
+            .map_err(TokenError::Store)?
+            .ok_or(TokenError::Invalid)?;
+
+        // Only a store ignoring the hash could return a token of another purpose.
+        if stored.purpose != purpose {
+            return Err(TokenError::Invalid);
+        }
+
+        if stored.expires_at <= Utc::now() {
+            return Err(TokenError::Expired);
+        }
+
+        Ok(stored.id)
+    }
+}
+
+/// Creates a new random url safe token with 256 bits of entropy.
+///
+/// # Examples
+/// ```rust
+/// use axum_session_auth::{generate_one_time_token, hash_one_time_token};
+///
+/// let token = generate_one_time_token();
+/// let hash = hash_one_time_token("verify_email", &token);
+/// ```
+///
+pub fn generate_one_time_token() -> String {
+    let mut bytes = [0u8; 32];
+    rand::thread_rng().fill_bytes(&mut bytes);
+    BASE64URL_NOPAD.encode(&bytes)
+}
+
+/// Hashes a one time token with its purpose for storage and lookup.
+///
+/// Tokens from generate_one_time_token are random enough that a fast hash is safe to use.
+pub fn hash_one_time_token(purpose: &str, token: &str) -> String {
+    let mut hasher = Sha256::new();
+    hasher.update(purpose.as_bytes());
+    // Purposes never contain a nul, so purpose and token can not run into each other.
+    hasher.update([0]);
+    hasher.update(token.as_bytes());
+    HEXLOWER.encode(&hasher.finalize())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn tokens() -> OneTimeTokens<i64, ()> {
+        OneTimeTokens::new(Arc::new(MemoryTokenStore::default()))
+    }
+
+    #[tokio::test]
+    async fn tokens_can_only_be_used_once() {
+        let tokens = tokens();
+        let token = tokens.issue_login(7, None).await.unwrap();
+
+        assert_eq!(tokens.consume(&token, "login", None).await.unwrap(), 7);
+        assert!(matches!(
+            tokens.consume(&token, "login", None).await,
+            Err(TokenError::Invalid)
+        ));
+    }
+
+    #[tokio::test(flavor = "multi_thread", worker_threads = 4)]
+    async fn concurrent_consumes_accept_the_token_once() {
+        let tokens = tokens();
+        let token = tokens.issue_login(7, None).await.unwrap();
+
+        let attempts: Vec<_> = (0..8)
+            .map(|_| {
+                let (tokens, token) = (tokens.clone(), token.clone());
+                tokio::spawn(async move { tokens.consume(&token, "login", None).await })
+            })
+            .collect();
+
+        let mut accepted = 0;
+
+        for attempt in attempts {
+            if attempt.await.unwrap().is_ok() {
+                accepted += 1;
+            }
+        }
+
+        assert_eq!(accepted, 1);
+    }
+
+    #[tokio::test]
+    async fn expired_tokens_are_rejected() {
+        let tokens = tokens().with_ttl(Duration::zero());
+        let token = tokens.issue_login(7, None).await.unwrap();
+
+        assert!(matches!(
+            tokens.consume(&token, "login", None).await,
+            Err(TokenError::Expired)
+        ));
+    }
+
+    #[tokio::test]
+    async fn wrong_purpose_does_not_use_up_the_token() {
+        let tokens = tokens();
+        let token = tokens.issue(7, "verify_email", None).await.unwrap();
+
+        assert!(matches!(
+            tokens.consume(&token, "login", None).await,
+            Err(TokenError::Invalid)
+        ));
+        assert_eq!(
+            tokens.consume(&token, "verify_email", None).await.unwrap(),
+            7
+        );
+    }
+}
@@ -0,0 +1,370 @@
+use crate::request::percent_encode;
+use anyhow::Error;
+use async_trait::async_trait;
+use data_encoding::{BASE32_NOPAD, HEXLOWER};
+use hmac::{Hmac, Mac};
+use rand::RngCore;
+use sha2::{Digest, Sha256, Sha512};
+use std::{
+    fmt,
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+/// Hash algorithm a Totp uses. Most authenticator apps only support Sha1.
+///
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum TotpAlgorithm {
+    #[default]
+    Sha1,
+    Sha256,
+    Sha512,
+}
+
+impl TotpAlgorithm {
+    fn as_str(&self) -> &'static str {
+        match self {
+            TotpAlgorithm::Sha1 => "SHA1",
+            TotpAlgorithm::Sha256 => "SHA256",
+            TotpAlgorithm::Sha512 => "SHA512",
+        }
+    }
+
+    fn hmac(&self, key: &[u8], msg: &[u8]) -> Vec<u8> {
+        fn sign<M: Mac + hmac::digest::KeyInit>(key: &[u8], msg: &[u8]) -> Vec<u8> {
+            let mut mac = <M as Mac>::new_from_slice(key).expect("HMAC accepts any key length");
+            mac.update(msg);
+            mac.finalize().into_bytes().to_vec()
+        }
+
+        match self {
+            TotpAlgorithm::Sha1 => sign::<Hmac<sha1::Sha1>>(key, msg),
+            TotpAlgorithm::Sha256 => sign::<Hmac<Sha256>>(key, msg),
+            TotpAlgorithm::Sha512 => sign::<Hmac<Sha512>>(key, msg),
+        }
+    }
+}
+
+/// Time based one time password generator and verifier, as in RFC 6238.
+///
+/// # Examples
+/// ```rust
+/// use axum_session_auth::Totp;
+///
+/// let totp = Totp::generate();
+/// let uri = totp.otpauth_uri("Example", "alice@example.com");
+///
+/// let code = totp.code_at(1_700_000_000);
+/// assert!(totp.verify_at(&code, None, 1_700_000_000).is_some());
+/// ```
+///
+#[derive(Clone)]
+pub struct Totp {
+    secret: Vec<u8>,
+    pub(crate) digits: u32,
+    pub(crate) step: u64,
+    pub(crate) skew: u64,
+    pub(crate) algorithm: TotpAlgorithm,
+}
+
+impl fmt::Debug for Totp {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("Totp")
+            .field("digits", &self.digits)
+            .field("step", &self.step)
+            .field("skew", &self.skew)
+            .field("algorithm", &self.algorithm)
+            .finish_non_exhaustive()
+    }
+}
+
+impl Totp {
+    /// Creates a Totp for an existing secret with 6 digits, a 30 second step and a skew of 1 step.
+    pub fn new(secret: Vec<u8>) -> Self {
+        Self {
+            secret,
+            digits: 6,
+            step: 30,
+            skew: 1,
+            algorithm: TotpAlgorithm::Sha1,
+        }
+    }
+
+    /// Creates a Totp with a new random 160 bit secret.
+    pub fn generate() -> Self {
+        let mut secret = vec![0u8; 20];
+        rand::thread_rng().fill_bytes(&mut secret);
+        Self::new(secret)
+    }
+
+    /// Creates a Totp from a base32 secret, as stored from secret_base32.
+    pub fn from_base32(secret: &str) -> Option<Self> {
+        let secret = secret.trim().trim_end_matches('=').to_uppercase();
+
+        BASE32_NOPAD.decode(secret.as_bytes()).ok().map(Self::new)
+    }
+
+    /// Returns the secret as unpadded base32, for storage or manual entry.
+    pub fn secret_base32(&self) -> String {
+        BASE32_NOPAD.encode(&self.secret)
+    }
+
+    /// Set's the amount of digits in a code. Defaults to 6.
+    ///
+    /// # Examples
+    /// ```rust
+    /// use axum_session_auth::Totp;
+    ///
+    /// let totp = Totp::generate().with_digits(8);
+    /// ```
+    ///
+    #[must_use]
+    pub fn with_digits(mut self, digits: u32) -> Self {
+        self.digits = digits.clamp(6, 9);
+        self
+    }
+
+    /// Set's how many seconds each code is valid for. Defaults to 30.
+    ///
+    /// # Examples
+    /// ```rust
+    /// use axum_session_auth::Totp;
+    ///
+    /// let totp = Totp::generate().with_step(60);
+    /// ```
+    ///
+    #[must_use]
+    pub fn with_step(mut self, step: u64) -> Self {
+        self.step = step.max(1);
+        self
+    }
+
+    /// Set's how many steps before and after the current one are accepted, for clock skew. Defaults to 1.
+    ///
+    /// # Examples
+    /// ```rust
+    /// use axum_session_auth::Totp;
+    ///
+    /// let totp = Totp::generate().with_skew(2);
+    /// ```
+    ///
+    #[must_use]
+    pub fn with_skew(mut self, skew: u64) -> Self {
+        self.skew = skew;
+        self
+    }
+
+    /// Set's the hash algorithm. Defaults to Sha1.
+    ///
+    /// # Examples
+    /// ```rust
+    /// use axum_session_auth::{Totp, TotpAlgorithm};
+    ///
+    /// let totp = Totp::generate().with_algorithm(TotpAlgorithm::Sha256);
+    /// ```
+    ///
+    #[must_use]
+    pub fn with_algorithm(mut self, algorithm: TotpAlgorithm) -> Self {
+        self.algorithm = algorithm;
+        self
+    }
+
+    /// Returns the otpauth:// URI authenticator apps read from a QR code.
+    pub fn otpauth_uri(&self, issuer: &str, account: &str) -> String {
+        format!(
+            "otpauth://totp/{}:{}?secret={}&issuer={}&algorithm={}&digits={}&period={}",
+            percent_encode(issuer),
+            percent_encode(account),
+            self.secret_base32(),
+            percent_encode(issuer),
+            self.algorithm.as_str(),
+            self.digits,
+            self.step,
+        )
+    }
+
+    fn code_for_step(&self, step: u64) -> String {
+        let hash = self.algorithm.hmac(&self.secret, &step.to_be_bytes());
+        let offset = (hash[hash.len() - 1] & 0x0f) as usize;
+        let binary = u32::from_be_bytes([
+            hash[offset] & 0x7f,
+            hash[offset + 1],
+            hash[offset + 2],
+            hash[offset + 3],
+        ]);
+
+        format!(
+            "{:0width$}",
+            binary % 10u32.pow(self.digits),
+            width = self.digits as usize
+        )
+    }
+
+    /// Returns the code for a unix timestamp in seconds.
+    pub fn code_at(&self, time: u64) -> String {
+        self.code_for_step(time / self.step)
+    }
+
+    /// Returns the code for the current time.
+    pub fn code(&self) -> String {
+        self.code_at(now())
+    }
+
+    /// Checks a code against the steps around a unix timestamp in seconds.
+    ///
+    /// Returns the step the code matched, which must be stored and passed back as
+    /// last_step on the next call so a code can not be used twice.
+    pub fn verify_at(&self, code: &str, last_step: Option<u64>, time: u64) -> Option<u64> {
+        let code = code.trim();
+        let current = time / self.step;
+
+        (current.saturating_sub(self.skew)..=current.saturating_add(self.skew))
+            .filter(|step| last_step.is_none_or(|last| *step > last))
+            .find(|step| constant_time_eq(self.code_for_step(*step).as_bytes(), code.as_bytes()))
+    }
+
+    /// Checks a code against the current time. See verify_at.
+    pub fn verify(&self, code: &str, last_step: Option<u64>) -> Option<u64> {
+        self.verify_at(code, last_step, now())
+    }
+}
+
+/// Creates count random recovery codes like `abcd-efgh-ijkl-mnop`.
+///
+/// Show them to the user once and store only their hash_recovery_code hashes.
+///
+/// # Examples
+/// ```rust
+/// use axum_session_auth::{generate_recovery_codes, hash_recovery_code};
+///
+/// let codes = generate_recovery_codes(10);
+/// let hashes: Vec<String> = codes.iter().map(|code| hash_recovery_code(code)).collect();
+/// ```
+///
+pub fn generate_recovery_codes(count: usize) -> Vec<String> {
+    let mut rng = rand::thread_rng();
+
+    (0..count)
+        .map(|_| {
+            let mut bytes = [0u8; 10];
+            rng.fill_bytes(&mut bytes);
+
+            BASE32_NOPAD
+                .encode(&bytes)
+                .to_lowercase()
+                .as_bytes()
+                .chunks(4)
+                .map(|chunk| String::from_utf8_lossy(chunk).into_owned())
+                .collect::<Vec<_>>()
+                .join("-")
+        })
+        .collect()
+}
+
+/// Hashes a recovery code for storage, ignoring case, spaces and dashes.
+pub fn hash_recovery_code(code: &str) -> String {
+    let normalized: String = code
+        .chars()
+        .filter(|c| c.is_ascii_alphanumeric())
+        .map(|c| c.to_ascii_lowercase())
+        .collect();
+
+    HEXLOWER.encode(&Sha256::digest(normalized.as_bytes()))
+}
+
+/// A users enrolled second factor, as returned by TotpStore::load.
+///
+#[derive(Debug, Clone)]
+pub struct SecondFactor {
+    pub totp: Totp,
+    /// Step of the last accepted code.
+    pub last_step: Option<u64>,
+    /// hash_recovery_code hashes of the unused recovery codes.
+    pub recovery_codes: Vec<String>,
+}
+
+/// Where verify_totp and verify_recovery_code load a users second factor from.
+///
+#[async_trait]
+pub trait TotpStore<Type, Pool>: Send + Sync
+where
+    Type: Send + Sync,
+    Pool: Send + Sync,
+{
+    /// Returns the users second factor, or None if they have not enrolled one.
+    async fn load(&self, id: &Type, pool: Option<&Pool>) -> Result<Option<SecondFactor>, Error>;
+
+    /// Stores the step of the code that was just accepted, if it is newer than the saved step.
+    ///
+    /// Must compare and store in one step, like `UPDATE ... WHERE last_step IS NULL OR
+    /// last_step < $1`, and return false if the step was not newer so a code sent twice
+    /// at the same time is only accepted once.
+    async fn save_last_step(
+        &self,
+        id: &Type,
+        step: u64,
+        pool: Option<&Pool>,
+    ) -> Result<bool, Error>;
+
+    /// Removes a used recovery code by its hash.
+    ///
+    /// Returns false if the code was already removed, so it is only accepted once.
+    async fn remove_recovery_code(
+        &self,
+        id: &Type,
+        hash: &str,
+        pool: Option<&Pool>,
+    ) -> Result<bool, Error>;
+}
+
+/// Why a second factor was not accepted.
+///
+/// Wrong codes are counted against the user id in the LoginLimiter set with
+/// AuthConfig::with_login_limiter, which blocks further codes with TooManyAttempts.
+///
+#[derive(Debug)]
+pub enum SecondFactorError {
+    /// There is no login waiting for a second factor.
+    NoPendingLogin,
+    /// The pending login waited longer than the configured second factor timeout.
+    Expired,
+    /// The pending user has no second factor enrolled.
+    NotEnrolled,
+    /// The code is wrong or was already used.
+    InvalidCode,
+    /// Too many wrong codes were entered for the user, the next code is checked after retry_after.
+    TooManyAttempts { retry_after: chrono::Duration },
+    /// The TotpStore failed.
+    Store(Error),
+}
+
+impl fmt::Display for SecondFactorError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            SecondFactorError::NoPendingLogin => {
+                f.write_str("no login is waiting for a second factor")
+            }
+            SecondFactorError::Expired => f.write_str("the pending login expired"),
+            SecondFactorError::NotEnrolled => f.write_str("the user has no second factor enrolled"),
+            SecondFactorError::InvalidCode => f.write_str("invalid or already used code"),
+            SecondFactorError::TooManyAttempts { retry_after } => write!(
+                f,
+                "too many wrong codes, retry in {} seconds",
+                retry_after.num_seconds()
+            ),
+            SecondFactorError::Store(err) => write!(f, "second factor store failed: {}", err),
+        }
+    }
+}
+
+impl std::error::Error for SecondFactorError {}
+
+fn now() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_secs())
+        .unwrap_or_default()
+}
+
+pub(crate) fn constant_time_eq(a: &[u8], b: &[u8]) -> bool {
+    a.len() == b.len() && a.iter().zip(b).fold(0u8, |acc, (x, y)| acc | (x ^ y)) == 0
+}
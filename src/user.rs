@@ -16,6 +16,20 @@ where
 {
     pub current_user: Option<User>,
     pub expires: DateTime<Utc>,
+    /// When current_user was loaded, used for refresh-ahead as expires slides on every hit.
+    pub(crate) loaded_at: DateTime<Utc>,
     pub phantom_pool: PhantomData<Pool>,
     pub phantom_type: PhantomData<Type>,
 }
+
+impl<User, Type, Pool> AuthUser<User, Type, Pool>
+where
+    User: Authentication<User, Type, Pool> + Send,
+    Type: Eq + Default + Clone + Send + Sync + Hash + Serialize + DeserializeOwned + 'static,
+    Pool: Clone + Send + Sync + fmt::Debug + 'static,
+{
+    /// Returns when current_user was loaded.
+    pub(crate) fn loaded_at(&self) -> DateTime<Utc> {
+        self.loaded_at
+    }
+}